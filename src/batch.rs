@@ -0,0 +1,50 @@
+use crate::data_type::EntryT;
+
+/// Puts and deletes that `LSMTree::write` applies together: they are logged as one
+/// record and become visible to readers at once, so a crash or a reader never sees
+/// only part of them. A later write to the same key in the batch wins.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) entries: Vec<EntryT>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut WriteBatch {
+        self.entries.push(EntryT::new(key.to_vec(), value.to_vec()));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut WriteBatch {
+        self.entries.push(EntryT::tombstone(key.to_vec()));
+        self
+    }
+
+    /// Number of puts and deletes in the batch
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[test]
+fn test_batch() {
+    let mut batch = WriteBatch::new();
+    assert!(batch.is_empty());
+    batch.put(b"a", b"1").delete(b"b").put(b"a", b"2");
+    assert_eq!(3, batch.len());
+    assert!(batch.entries[1].is_tombstone());
+    assert_eq!(b"2".to_vec(), batch.entries[2].value);
+    batch.clear();
+    assert!(batch.is_empty());
+}
@@ -1,104 +1,242 @@
 use crate::data_type::{EntryT, KeyT, ValueT};
+use crate::memtable::MemTable;
+use std::cmp::Ordering;
 use std::collections::BTreeSet;
-use std::ops::Bound::Included;
+use std::mem::size_of;
+use std::ops::Bound;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::RwLock;
 
+//an entry and the sequence number it was written with, ordered by key and then newest
+//first
+struct Versioned {
+    entry: EntryT,
+    seq: u64,
+}
+
+impl Versioned {
+    //an entry to look up key written with seq with
+    fn probe(key: &KeyT, seq: u64) -> Versioned {
+        Versioned {
+            entry: EntryT::new(key.clone(), ValueT::default()),
+            seq,
+        }
+    }
+}
+
+impl Ord for Versioned {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.entry
+            .key
+            .cmp(&other.entry.key)
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Versioned {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Versioned {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Versioned {}
+
+/// The memtable kept in a `BTreeSet`. Like the skiplist it keeps every write, so readers
+/// can stop at a sequence number, but inserts take a lock, so they run one at a time.
+#[derive(Default)]
 pub struct Buffer {
-    pub max_size: usize,
-    pub entries: BTreeSet<EntryT>,
+    entries: RwLock<BTreeSet<Versioned>>,
+    usage: AtomicUsize,
+    sequence: AtomicU64,
 }
 
 impl Buffer {
-    pub fn new(size: usize) -> Buffer {
-        Buffer {
-            max_size: size,
-            entries: BTreeSet::new(),
-        }
+    pub fn new() -> Buffer {
+        Buffer::default()
     }
+}
 
-    pub fn get(&self, key: &KeyT) -> Option<ValueT> {
-        let search_entry = EntryT {
-            key: key.clone(),
-            value: ValueT::default(),
-        };
-        if let Some(entry) = self.entries.get(&search_entry) {
-            Some(entry.value.clone())
-        } else {
-            None
-        }
+fn charge(entry: &EntryT) -> usize {
+    size_of::<Versioned>() + entry.key.len() + entry.value.len()
+}
+
+//the bound before every version of the keys within start
+fn lower(start: Bound<&KeyT>) -> Bound<Versioned> {
+    match start {
+        Included(key) => Included(Versioned::probe(key, u64::MAX)),
+        Excluded(key) => Excluded(Versioned::probe(key, 0)),
+        Unbounded => Unbounded,
+    }
+}
+
+//the bound after every version of the keys within end
+fn upper(end: Bound<&KeyT>) -> Bound<Versioned> {
+    match end {
+        Included(key) => Included(Versioned::probe(key, 0)),
+        Excluded(key) => Excluded(Versioned::probe(key, u64::MAX)),
+        Unbounded => Unbounded,
+    }
+}
+
+impl MemTable for Buffer {
+    //return the latest entry of key, which may be a tombstone
+    fn get(&self, key: &KeyT) -> Option<EntryT> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .range((lower(Included(key)), Unbounded))
+            .next()
+            .filter(|found| found.entry.key == *key)
+            .map(|found| found.entry.clone())
     }
 
-    pub fn range(&self, start: &KeyT, end: &KeyT) -> Vec<EntryT> {
-        let lower_bound = EntryT {
-            key: start.clone(),
-            value: ValueT::default(),
-        };
-        let upper_bound = EntryT {
-            key: end.clone(),
-            value: ValueT::default(),
-        };
-        let mut res: Vec<EntryT> = Vec::new();
-        for elem in self
+    //insert entry next to the older and newer entries of its key
+    fn put(&self, entry: EntryT, seq: u64) {
+        let mut entries = self.entries.write().unwrap();
+        self.usage
+            .fetch_add(charge(&entry), AtomicOrdering::Relaxed);
+        self.sequence.fetch_max(seq + 1, AtomicOrdering::Relaxed);
+        entries.insert(Versioned { entry, seq });
+    }
+
+    fn range(
+        &self,
+        start: Bound<&KeyT>,
+        end: Bound<&KeyT>,
+    ) -> Box<dyn Iterator<Item = EntryT> + '_> {
+        //BTreeSet::range panics on such bounds
+        match (start, end) {
+            (Included(s), Included(e)) if s > e => return Box::new(std::iter::empty()),
+            (Included(s), Excluded(e))
+            | (Excluded(s), Included(e))
+            | (Excluded(s), Excluded(e))
+                if s >= e =>
+            {
+                return Box::new(std::iter::empty())
+            }
+            _ => {}
+        }
+        //the lock can not outlive this call, so the entries are copied out
+        let mut entries: Vec<EntryT> = Vec::new();
+        for found in self
             .entries
-            .range((Included(lower_bound), Included(upper_bound)))
+            .read()
+            .unwrap()
+            .range((lower(start), upper(end)))
         {
-            res.push(elem.clone());
+            //the newest entry of a key comes first
+            if entries
+                .last()
+                .is_none_or(|last| last.key != found.entry.key)
+            {
+                entries.push(found.entry.clone());
+            }
         }
-        res
+        Box::new(entries.into_iter())
     }
 
-    pub fn put(&mut self, key: KeyT, value: ValueT) {
-        let entry = EntryT {
-            key: key,
-            value: value,
-        };
-        self.entries.replace(entry);
+    fn first_at(&self, start: Bound<&KeyT>, seq: u64) -> Option<EntryT> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .range((lower(start), Unbounded))
+            .find(|found| found.seq < seq)
+            .map(|found| found.entry.clone())
     }
 
-    pub fn empty(&mut self) {
-        self.entries.clear();
+    fn last_at(&self, end: Bound<&KeyT>, seq: u64) -> Option<EntryT> {
+        let entries = self.entries.read().unwrap();
+        //backwards the entries of a key come oldest first, so the last one below seq wins
+        let mut found: Option<&Versioned> = None;
+        for entry in entries.range((Unbounded, upper(end))).rev() {
+            if found.is_some_and(|found| found.entry.key != entry.entry.key) {
+                break;
+            }
+            if entry.seq < seq {
+                found = Some(entry);
+            }
+        }
+        found.map(|found| found.entry.clone())
     }
 
-    pub fn full(&self) -> bool {
-        if self.entries.len() == self.max_size {
-            true
-        } else {
-            false
-        }
+    fn sequence(&self) -> u64 {
+        self.sequence.load(AtomicOrdering::Relaxed)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    fn approximate_memory_usage(&self) -> usize {
+        self.usage.load(AtomicOrdering::Relaxed)
     }
 }
 
 #[test]
 fn test_size() {
-    let mut buf = Buffer::new(10);
+    let buf = Buffer::new();
     buf.put(
-        "helloworld".as_bytes().to_vec(),
-        "worldhello".as_bytes().to_vec(),
+        EntryT::new(
+            "helloworld".as_bytes().to_vec(),
+            "worldhello".as_bytes().to_vec(),
+        ),
+        0,
+    );
+    buf.put(
+        EntryT::new("hello".as_bytes().to_vec(), "world".as_bytes().to_vec()),
+        1,
+    );
+    assert_eq!(2, buf.len());
+    let usage = buf.approximate_memory_usage();
+    assert_eq!(2 * size_of::<Versioned>() + 30, usage);
+    //an overwrite is charged in full, the older entry stays for older readers
+    buf.put(
+        EntryT::new("hello".as_bytes().to_vec(), "world!".as_bytes().to_vec()),
+        2,
+    );
+    assert_eq!(3, buf.len());
+    assert_eq!(
+        usage + size_of::<Versioned>() + 11,
+        buf.approximate_memory_usage()
     );
-    buf.put("hello".as_bytes().to_vec(), "world".as_bytes().to_vec());
-    assert_eq!(2, buf.entries.len());
-    assert_eq!(10, buf.max_size);
-    assert!(false == buf.full());
 }
 
 #[test]
 fn test_put_get() {
-    let mut buf = Buffer::new(10);
+    let buf = Buffer::new();
     for i in 0..10u8 {
-        buf.put(vec![i], vec![i]);
+        buf.put(EntryT::new(vec![i], vec![i]), i as u64);
     }
     for j in 0..10u8 {
-        assert_eq!(vec![j], buf.get(&vec![j]).unwrap());
+        assert_eq!(vec![j], buf.get(&vec![j]).unwrap().value);
     }
 }
 
+#[test]
+fn test_del() {
+    let buf = Buffer::new();
+    buf.put(EntryT::new(vec![1], "TOMBSTONE".as_bytes().to_vec()), 0);
+    assert!(!buf.get(&vec![1]).unwrap().is_tombstone());
+    buf.put(EntryT::tombstone(vec![1]), 1);
+    assert_eq!(2, buf.len());
+    assert!(buf.get(&vec![1]).unwrap().is_tombstone());
+}
+
 #[test]
 fn test_range() {
-    let mut buf = Buffer::new(10);
+    let buf = Buffer::new();
     for i in 0..10u8 {
-        buf.put(vec![i], vec![i]);
+        buf.put(EntryT::new(vec![i], vec![i]), i as u64);
     }
+    let range: Vec<EntryT> = buf.range(Included(&vec![1]), Included(&vec![5])).collect();
     for j in 0..5u8 {
-        assert_eq!(vec![j + 1], buf.range(&vec![1], &vec![5])[j as usize].value);
+        assert_eq!(vec![j + 1], range[j as usize].value);
     }
     //println!("{:?}", buf.range(&vec![1], &vec![5])[0].value);
+    assert_eq!(0, buf.range(Included(&vec![5]), Excluded(&vec![5])).count());
 }
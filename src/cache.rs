@@ -0,0 +1,146 @@
+//pages read from run files are kept here as they are in the file, so a hot page is not
+//read again and is still searched in place. one cache can serve the runs of any number
+//of trees. pages are charged by their size and the least recently used ones go first.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+//every run gets its own id, so the pages of a deleted run are never mistaken for the
+//pages of a later run that reuses its file name
+static NEXT_RUN_ID: AtomicU64 = AtomicU64::new(0);
+
+pub fn new_run_id() -> u64 {
+    NEXT_RUN_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+//run id and page index
+type PageKey = (u64, usize);
+
+struct CachedPage {
+    data: Arc<[u8]>,
+    last_use: u64,
+}
+
+#[derive(Default)]
+struct LruState {
+    pages: HashMap<PageKey, CachedPage>,
+    //keys by last use, least recently used first
+    order: BTreeMap<u64, PageKey>,
+    clock: u64,
+    usage: usize,
+}
+
+/// A LRU cache of run pages, holding at most `capacity` bytes of pages
+pub struct BlockCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            capacity,
+            state: Mutex::new(LruState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached bytes of page `page_index` of run `run_id` and marks them as
+    /// just used
+    pub fn get(&self, run_id: u64, page_index: usize) -> Option<Arc<[u8]>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.clock += 1;
+        match state.pages.get_mut(&(run_id, page_index)) {
+            Some(page) => {
+                state.order.remove(&page.last_use);
+                page.last_use = state.clock;
+                state.order.insert(state.clock, (run_id, page_index));
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(page.data.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches the bytes of a page, evicting the least recently used pages to make room.
+    /// Pages larger than the whole cache are not kept.
+    pub fn insert(&self, run_id: u64, page_index: usize, data: Arc<[u8]>) {
+        let charge = data.len();
+        if charge > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(old) = state.pages.remove(&(run_id, page_index)) {
+            state.order.remove(&old.last_use);
+            state.usage -= old.data.len();
+        }
+        while state.usage + charge > self.capacity {
+            let (_, key) = state.order.pop_first().unwrap();
+            state.usage -= state.pages.remove(&key).unwrap().data.len();
+        }
+        state.clock += 1;
+        state.order.insert(state.clock, (run_id, page_index));
+        state.pages.insert(
+            (run_id, page_index),
+            CachedPage {
+                data,
+                last_use: state.clock,
+            },
+        );
+        state.usage += charge;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Bytes of pages in the cache
+    pub fn usage(&self) -> usize {
+        self.state.lock().unwrap().usage
+    }
+
+    /// Lookups that found their page in the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Lookups that had to read their page from the run file
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[test]
+fn test_lru() {
+    let page = |byte: u8, len: usize| -> Arc<[u8]> { Arc::from(vec![byte; len]) };
+    let cache = BlockCache::new(300);
+    cache.insert(0, 0, page(0, 100));
+    cache.insert(0, 1, page(1, 100));
+    cache.insert(1, 0, page(2, 100));
+    assert_eq!(300, cache.usage());
+    //page (0, 0) becomes the most recently used, so (0, 1) goes first
+    assert_eq!(page(0, 100), cache.get(0, 0).unwrap());
+    cache.insert(1, 1, page(3, 100));
+    assert!(cache.get(0, 1).is_none());
+    assert_eq!(page(2, 100), cache.get(1, 0).unwrap());
+    assert_eq!(page(3, 100), cache.get(1, 1).unwrap());
+    assert_eq!(300, cache.usage());
+    assert_eq!(3, cache.hits());
+    assert_eq!(1, cache.misses());
+
+    //replacing a page releases its old charge
+    cache.insert(1, 1, page(4, 50));
+    assert_eq!(250, cache.usage());
+    assert_eq!(page(4, 50), cache.get(1, 1).unwrap());
+    cache.insert(2, 0, page(5, 301));
+    assert!(cache.get(2, 0).is_none());
+    assert_eq!(250, cache.usage());
+}
@@ -0,0 +1,67 @@
+//little endian encoding of the metadata stored in run files and in the manifest
+use std::convert::TryInto;
+
+pub fn put_u32(dst: &mut Vec<u8>, value: u32) {
+    dst.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u64(dst: &mut Vec<u8>, value: u64) {
+    dst.extend_from_slice(&value.to_le_bytes());
+}
+
+//bytes prefixed with their length as u32
+pub fn put_bytes(dst: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(dst, bytes.len() as u32);
+    dst.extend_from_slice(bytes);
+}
+
+/// Reads back what the put_* functions wrote. Every read returns None once the data runs out.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    //true once every byte has been read
+    pub fn done(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+#[test]
+fn test_put_decode() {
+    let mut data = Vec::new();
+    put_u32(&mut data, 7);
+    put_bytes(&mut data, b"key");
+    put_u64(&mut data, u64::MAX);
+    let mut decoder = Decoder::new(&data);
+    assert_eq!(Some(7), decoder.u32());
+    assert_eq!(Some(&b"key"[..]), decoder.bytes());
+    assert!(!decoder.done());
+    assert_eq!(Some(u64::MAX), decoder.u64());
+    assert!(decoder.done());
+    assert_eq!(None, decoder.u32());
+}
@@ -1,5 +1,5 @@
 use std::cmp::Ordering;
-use std::hash::Hash;
+use std::convert::TryInto;
 
 /*
  *  use for components
@@ -7,23 +7,117 @@ use std::hash::Hash;
 pub type KeyT = Vec<u8>;
 pub type ValueT = Vec<u8>;
 
-pub static KEY_SIZE: usize = 8;
-pub static VALUE_SIZE: usize = 24;
-pub static ENTRY_SIZE: usize = 32;
+//an encoded entry starts with its kind, then the key length and the value length as little endian u32
+pub static ENTRY_HEADER_SIZE: usize = 9;
 pub static FILENAME_SIZE: usize = 32;
-pub static TOMBSTONE: &str = "TOMBSTONE";
-#[derive(Eq, Default, Debug, Clone, Hash)]
+
+/// What an entry does to its key. The discriminant is the byte stored on disk.
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default)]
+#[repr(u8)]
+pub enum EntryKind {
+    #[default]
+    Put = 0,
+    //tombstone, hides every older value of the key
+    Delete = 1,
+}
+
+impl EntryKind {
+    pub fn from_u8(byte: u8) -> Option<EntryKind> {
+        match byte {
+            0 => Some(EntryKind::Put),
+            1 => Some(EntryKind::Delete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Eq, Default, Debug, Clone)]
 pub struct Entry {
     pub key: KeyT,
     pub value: ValueT,
+    pub kind: EntryKind,
 }
 
 impl Entry {
     pub fn new(k: KeyT, val: ValueT) -> Entry {
-        Entry { key: k, value: val }
+        Entry {
+            key: k,
+            value: val,
+            kind: EntryKind::Put,
+        }
+    }
+
+    pub fn tombstone(k: KeyT) -> Entry {
+        Entry {
+            key: k,
+            value: ValueT::new(),
+            kind: EntryKind::Delete,
+        }
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.kind == EntryKind::Delete
+    }
+
+    /// Returns the number of bytes this entry takes once encoded
+    pub fn encoded_len(&self) -> usize {
+        ENTRY_HEADER_SIZE + self.key.len() + self.value.len()
+    }
+
+    /// Writes the length-prefixed encoding of this entry to the front of `dst`
+    /// and returns the number of bytes written.
+    pub fn encode_to(&self, dst: &mut [u8]) -> usize {
+        let key_end = ENTRY_HEADER_SIZE + self.key.len();
+        let value_end = key_end + self.value.len();
+        dst[0] = self.kind as u8;
+        dst[1..5].copy_from_slice(&(self.key.len() as u32).to_le_bytes());
+        dst[5..9].copy_from_slice(&(self.value.len() as u32).to_le_bytes());
+        dst[ENTRY_HEADER_SIZE..key_end].copy_from_slice(&self.key);
+        dst[key_end..value_end].copy_from_slice(&self.value);
+        value_end
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res = vec![0; self.encoded_len()];
+        self.encode_to(&mut res);
+        res
+    }
+
+    /// Decodes the entry at the front of `src`. Returns the entry and the number
+    /// of bytes it took, or None if `src` does not hold a complete entry.
+    pub fn decode(src: &[u8]) -> Option<(Entry, usize)> {
+        if src.len() < ENTRY_HEADER_SIZE {
+            return None;
+        }
+        let kind = EntryKind::from_u8(src[0])?;
+        let key_len = u32::from_le_bytes(src[1..5].try_into().unwrap()) as usize;
+        let value_len = u32::from_le_bytes(src[5..9].try_into().unwrap()) as usize;
+        let key_end = ENTRY_HEADER_SIZE + key_len;
+        let value_end = key_end + value_len;
+        if src.len() < value_end {
+            return None;
+        }
+        Some((
+            Entry {
+                key: src[ENTRY_HEADER_SIZE..key_end].to_vec(),
+                value: src[key_end..value_end].to_vec(),
+                kind,
+            },
+            value_end,
+        ))
     }
 }
 
+/// Returns the key of the entry at the front of `src` without copying it, or None if
+/// `src` does not hold the whole key.
+pub fn decode_key(src: &[u8]) -> Option<&[u8]> {
+    if src.len() < ENTRY_HEADER_SIZE {
+        return None;
+    }
+    let key_len = u32::from_le_bytes(src[1..5].try_into().unwrap()) as usize;
+    src.get(ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + key_len)
+}
+
 impl Ord for Entry {
     fn cmp(&self, other: &Self) -> Ordering {
         self.key.cmp(&other.key)
@@ -49,3 +143,25 @@ pub type EntryT = Entry;
  */
 pub static BLOOM_SIZE: u64 = 10000000;
 pub static HASHES: u64 = 5;
+
+#[test]
+fn test_encode_decode() {
+    let entry = Entry::new(
+        "2b4c1a8e-0d6f-4f4e-9a53-6cbe2b0f9e31".as_bytes().to_vec(),
+        r#"{"name": "alice", "tags": ["a", "b"]}"#.as_bytes().to_vec(),
+    );
+    let encoded = entry.encode();
+    assert_eq!(entry.encoded_len(), encoded.len());
+    let (decoded, used) = Entry::decode(&encoded).unwrap();
+    assert_eq!(encoded.len(), used);
+    assert_eq!(entry.key, decoded.key);
+    assert_eq!(entry.value, decoded.value);
+    assert!(Entry::decode(&encoded[..encoded.len() - 1]).is_none());
+    assert_eq!(Some(&entry.key[..]), decode_key(&encoded));
+    assert!(decode_key(&encoded[..ENTRY_HEADER_SIZE + 3]).is_none());
+
+    let tombstone = Entry::tombstone(b"TOMBSTONE".to_vec());
+    let (decoded, _) = Entry::decode(&tombstone.encode()).unwrap();
+    assert!(decoded.is_tombstone());
+    assert!(!Entry::new(b"k".to_vec(), b"TOMBSTONE".to_vec()).is_tombstone());
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong in the store
+#[derive(Debug)]
+pub enum Error {
+    /// An operation on a file of the tree failed
+    Io(io::Error),
+    /// A file of the tree does not hold what it should, e.g. a bad checksum
+    Corruption(String),
+    /// The caller passed a value the tree can not work with
+    InvalidArgument(String),
+    /// Every level is full, so the buffer can not be flushed
+    NoSpace,
+    /// Another open tree holds the lock of this data directory
+    AlreadyInUse(PathBuf),
+    /// The tree has been closed and does not take writes anymore
+    Closed,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn corruption<S: Into<String>>(msg: S) -> Error {
+        Error::Corruption(msg.into())
+    }
+
+    pub fn invalid_argument<S: Into<String>>(msg: S) -> Error {
+        Error::InvalidArgument(msg.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Corruption(msg) => write!(f, "corruption: {}", msg),
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::NoSpace => write!(f, "no more space in tree"),
+            Error::AlreadyInUse(path) => write!(f, "{:?} is already in use", path),
+            Error::Closed => write!(f, "tree is closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+//a failed background job keeps its error to report it to every later write
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        match self {
+            //io::Error can not be cloned, the copy keeps its kind and message
+            Error::Io(e) => Error::Io(io::Error::new(e.kind(), e.to_string())),
+            Error::Corruption(msg) => Error::Corruption(msg.clone()),
+            Error::InvalidArgument(msg) => Error::InvalidArgument(msg.clone()),
+            Error::NoSpace => Error::NoSpace,
+            Error::AlreadyInUse(path) => Error::AlreadyInUse(path.clone()),
+            Error::Closed => Error::Closed,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[test]
+fn test_display() {
+    let e: Error = io::Error::new(io::ErrorKind::NotFound, "run_file-3.txt").into();
+    assert!(matches!(e, Error::Io(_)));
+    assert_eq!("I/O error: run_file-3.txt", e.to_string());
+    let copy = e.clone();
+    assert!(matches!(&copy, Error::Io(io) if io.kind() == io::ErrorKind::NotFound));
+    assert_eq!(e.to_string(), copy.to_string());
+    assert_eq!(
+        "corruption: bad run file footer",
+        Error::corruption("bad run file footer").to_string()
+    );
+}
@@ -0,0 +1,473 @@
+//streaming reads over the whole tree. every source (the buffer and each run) is a cursor
+//sitting in a gap between two of its entries. all cursors always sit in the same gap of
+//the merged key order, so moving either way only needs the entries right next to the gap,
+//a run never has more than one page in memory and a memtable not more than those entries.
+use crate::data_type::{EntryT, KeyT, ValueT};
+use crate::error::Result;
+use crate::memtable::MemTable;
+use crate::run::Run;
+use std::ops::Bound;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::Arc;
+
+trait Cursor {
+    //move to the gap before the first entry with a key >= key
+    fn seek(&mut self, key: &KeyT) -> Result<()>;
+    //move to the gap after the last entry
+    fn seek_to_last(&mut self) -> Result<()>;
+    //the entry right after the gap
+    fn peek_next(&mut self) -> Result<Option<&EntryT>>;
+    //the entry right before the gap
+    fn peek_prev(&mut self) -> Result<Option<&EntryT>>;
+    //move the gap over the entry found by peek_next or peek_prev
+    fn step_next(&mut self);
+    fn step_prev(&mut self);
+}
+
+//a gap between the keys of a memtable
+enum Gap {
+    First,
+    Before(KeyT),
+    After(KeyT),
+    Last,
+}
+
+//reads a memtable as of a sequence number, an entry at a time, so the buffer is not
+//copied and the writes going into it meanwhile are not seen
+struct MemCursor {
+    table: Arc<dyn MemTable>,
+    seq: u64,
+    gap: Gap,
+    //the entries right after and right before the gap, once looked up
+    next: Option<Option<EntryT>>,
+    prev: Option<Option<EntryT>>,
+}
+
+impl MemCursor {
+    fn new(table: Arc<dyn MemTable>, seq: u64) -> MemCursor {
+        MemCursor {
+            table,
+            seq,
+            gap: Gap::First,
+            next: None,
+            prev: None,
+        }
+    }
+
+    fn move_to(&mut self, gap: Gap) {
+        self.gap = gap;
+        self.next = None;
+        self.prev = None;
+    }
+}
+
+impl Cursor for MemCursor {
+    fn seek(&mut self, key: &KeyT) -> Result<()> {
+        self.move_to(Gap::Before(key.clone()));
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.move_to(Gap::Last);
+        Ok(())
+    }
+
+    fn peek_next(&mut self) -> Result<Option<&EntryT>> {
+        if self.next.is_none() {
+            self.next = Some(match &self.gap {
+                Gap::First => self.table.first_at(Unbounded, self.seq),
+                Gap::Before(key) => self.table.first_at(Included(key), self.seq),
+                Gap::After(key) => self.table.first_at(Excluded(key), self.seq),
+                Gap::Last => None,
+            });
+        }
+        Ok(self.next.as_ref().and_then(Option::as_ref))
+    }
+
+    fn peek_prev(&mut self) -> Result<Option<&EntryT>> {
+        if self.prev.is_none() {
+            self.prev = Some(match &self.gap {
+                Gap::First => None,
+                Gap::Before(key) => self.table.last_at(Excluded(key), self.seq),
+                Gap::After(key) => self.table.last_at(Included(key), self.seq),
+                Gap::Last => self.table.last_at(Unbounded, self.seq),
+            });
+        }
+        Ok(self.prev.as_ref().and_then(Option::as_ref))
+    }
+
+    //the entry stepped over is the one right on the other side of the gap now
+    fn step_next(&mut self) {
+        if let Some(Some(entry)) = self.next.take() {
+            self.gap = Gap::After(entry.key.clone());
+            self.prev = Some(Some(entry));
+        }
+    }
+
+    fn step_prev(&mut self) {
+        if let Some(Some(entry)) = self.prev.take() {
+            self.gap = Gap::Before(entry.key.clone());
+            self.next = Some(Some(entry));
+        }
+    }
+}
+
+//reads a sealed run one page at a time. it holds the run, so a run merged away meanwhile
+//stays readable until the cursor is dropped
+struct RunCursor {
+    run: Arc<Run>,
+    page: usize,
+    //entries of page, pos is the gap inside them
+    entries: Vec<EntryT>,
+    pos: usize,
+}
+
+impl RunCursor {
+    fn new(run: Arc<Run>) -> RunCursor {
+        RunCursor {
+            run,
+            page: 0,
+            entries: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn load(&mut self, page: usize) -> Result<()> {
+        self.entries = self.run.read_page(page)?;
+        self.page = page;
+        Ok(())
+    }
+}
+
+impl Cursor for RunCursor {
+    fn seek(&mut self, key: &KeyT) -> Result<()> {
+        if self.run.num_pages() == 0 {
+            return Ok(());
+        }
+        //the page that would hold key
+        let page = match self.run.fence_pointers.binary_search(key) {
+            Ok(find) => find,
+            Err(0) => 0,
+            Err(not) => not - 1,
+        };
+        self.load(page)?;
+        self.pos = self.entries.partition_point(|entry| entry.key < *key);
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        if self.run.num_pages() == 0 {
+            return Ok(());
+        }
+        self.load(self.run.num_pages() - 1)?;
+        self.pos = self.entries.len();
+        Ok(())
+    }
+
+    fn peek_next(&mut self) -> Result<Option<&EntryT>> {
+        //the end of a page is the same gap as the start of the next one
+        if self.pos == self.entries.len() {
+            if self.page + 1 >= self.run.num_pages() {
+                return Ok(None);
+            }
+            self.load(self.page + 1)?;
+            self.pos = 0;
+        }
+        Ok(self.entries.get(self.pos))
+    }
+
+    fn peek_prev(&mut self) -> Result<Option<&EntryT>> {
+        if self.pos == 0 {
+            if self.page == 0 || self.entries.is_empty() {
+                return Ok(None);
+            }
+            self.load(self.page - 1)?;
+            self.pos = self.entries.len();
+        }
+        Ok(self.entries.get(self.pos - 1))
+    }
+
+    fn step_next(&mut self) {
+        self.pos += 1;
+    }
+
+    fn step_prev(&mut self) {
+        self.pos -= 1;
+    }
+}
+
+/// Iterator over the live keys of a tree and their values, in key order.
+///
+/// Like a cursor it sits between two keys: `next` returns the key after it and `prev`
+/// the key before it, so calling `prev` right after `next` returns the same key again.
+/// Entries are read lazily, a page at a time, and the newest value of every key wins.
+/// It reads the tree as it was when the iterator was made, later writes are not seen.
+/// After an error the position of the iterator is unspecified.
+pub struct TreeIter {
+    //newest first: the buffer, the full buffers waiting for their flush, then the runs of
+    //level 0, of level 1 and so on
+    sources: Vec<Box<dyn Cursor + Send>>,
+    start: Bound<KeyT>,
+    end: Bound<KeyT>,
+}
+
+impl TreeIter {
+    //an iterator over the entries of the memtables, read as of their sequence numbers, and
+    //of the runs, both newest first, positioned before the first key within the bounds
+    pub(crate) fn new(
+        memtables: Vec<(Arc<dyn MemTable>, u64)>,
+        runs: Vec<Arc<Run>>,
+        start: Bound<KeyT>,
+        end: Bound<KeyT>,
+    ) -> Result<TreeIter> {
+        let mut sources: Vec<Box<dyn Cursor + Send>> =
+            Vec::with_capacity(memtables.len() + runs.len());
+        for (table, seq) in memtables {
+            sources.push(Box::new(MemCursor::new(table, seq)));
+        }
+        for run in runs {
+            sources.push(Box::new(RunCursor::new(run)));
+        }
+        let mut iter = TreeIter {
+            sources,
+            start,
+            end,
+        };
+        iter.seek(&[])?;
+        Ok(iter)
+    }
+
+    fn below_start(&self, key: &KeyT) -> bool {
+        match &self.start {
+            Included(start) => key < start,
+            Excluded(start) => key <= start,
+            Unbounded => false,
+        }
+    }
+
+    fn above_end(&self, key: &KeyT) -> bool {
+        match &self.end {
+            Included(end) => key > end,
+            Excluded(end) => key >= end,
+            Unbounded => false,
+        }
+    }
+
+    /// Moves the iterator so that `next` returns the first key `>= key`.
+    /// Keys outside the range of the iterator are clamped to it.
+    pub fn seek(&mut self, key: &[u8]) -> Result<()> {
+        let key = key.to_vec();
+        let target = match &self.start {
+            Included(start) | Excluded(start) if key < *start => start.clone(),
+            _ => key,
+        };
+        if let Included(end) | Excluded(end) = &self.end {
+            if target > *end {
+                //past the end, stop right behind the last key in range so prev does not have
+                //to walk back over every key in between
+                return self.seek_to_last();
+            }
+        }
+        for source in self.sources.iter_mut() {
+            source.seek(&target)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the iterator behind the last key of its range, so that `prev` returns that key
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        let inclusive = matches!(self.end, Included(_));
+        for source in self.sources.iter_mut() {
+            match &self.end {
+                Included(end) | Excluded(end) => {
+                    source.seek(end)?;
+                    if inclusive && source.peek_next()?.is_some_and(|e| e.key == *end) {
+                        source.step_next();
+                    }
+                }
+                Unbounded => source.seek_to_last()?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns this into an iterator going from the last key of the range down to the first
+    pub fn into_rev(mut self) -> Result<RevTreeIter> {
+        self.seek_to_last()?;
+        Ok(RevTreeIter { iter: self })
+    }
+
+    fn step_forward(&mut self) -> Result<Option<(KeyT, ValueT)>> {
+        loop {
+            //the smallest key after the gap. on ties the newest source comes first
+            let mut newest: Option<&EntryT> = None;
+            for source in self.sources.iter_mut() {
+                if let Some(entry) = source.peek_next()? {
+                    if newest.is_none_or(|n| entry.key < n.key) {
+                        newest = Some(entry);
+                    }
+                }
+            }
+            let entry = match newest {
+                Some(entry) => entry.clone(),
+                None => return Ok(None),
+            };
+            if self.above_end(&entry.key) {
+                return Ok(None);
+            }
+            //move every source over the key, older entries of it are hidden
+            for source in self.sources.iter_mut() {
+                if source.peek_next()?.is_some_and(|e| e.key == entry.key) {
+                    source.step_next();
+                }
+            }
+            if !entry.is_tombstone() && !self.below_start(&entry.key) {
+                return Ok(Some((entry.key, entry.value)));
+            }
+        }
+    }
+
+    fn step_backward(&mut self) -> Result<Option<(KeyT, ValueT)>> {
+        loop {
+            //the largest key before the gap. on ties the newest source comes first
+            let mut newest: Option<&EntryT> = None;
+            for source in self.sources.iter_mut() {
+                if let Some(entry) = source.peek_prev()? {
+                    if newest.is_none_or(|n| entry.key > n.key) {
+                        newest = Some(entry);
+                    }
+                }
+            }
+            let entry = match newest {
+                Some(entry) => entry.clone(),
+                None => return Ok(None),
+            };
+            if self.below_start(&entry.key) {
+                return Ok(None);
+            }
+            for source in self.sources.iter_mut() {
+                if source.peek_prev()?.is_some_and(|e| e.key == entry.key) {
+                    source.step_prev();
+                }
+            }
+            if !entry.is_tombstone() && !self.above_end(&entry.key) {
+                return Ok(Some((entry.key, entry.value)));
+            }
+        }
+    }
+
+    /// Returns the key before the iterator and its value, and moves the iterator back over it
+    pub fn prev(&mut self) -> Option<Result<(KeyT, ValueT)>> {
+        self.step_backward().transpose()
+    }
+}
+
+impl Iterator for TreeIter {
+    type Item = Result<(KeyT, ValueT)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step_forward().transpose()
+    }
+}
+
+/// Iterator over the live keys of a tree and their values, in descending key order
+pub struct RevTreeIter {
+    iter: TreeIter,
+}
+
+impl Iterator for RevTreeIter {
+    type Item = Result<(KeyT, ValueT)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.prev()
+    }
+}
+
+/// Keys and values returned by a scan with a limit
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RangePage {
+    pub entries: Vec<(KeyT, ValueT)>,
+    /// The last key returned, if the range holds more keys. The next page starts right
+    /// after it: pass `Bound::Excluded(continuation)` as the start of the range, or as
+    /// the end for a descending scan.
+    pub continuation: Option<KeyT>,
+}
+
+impl RangePage {
+    //takes up to limit items from iter
+    pub(crate) fn collect<I>(mut iter: I, limit: usize) -> Result<RangePage>
+    where
+        I: Iterator<Item = Result<(KeyT, ValueT)>>,
+    {
+        let mut page = RangePage::default();
+        for item in iter.by_ref().take(limit) {
+            page.entries.push(item?);
+        }
+        if iter.next().transpose()?.is_some() {
+            page.continuation = page.entries.last().map(|(key, _)| key.clone());
+        }
+        Ok(page)
+    }
+}
+
+#[test]
+fn test_merge_cursors() {
+    use crate::buffer::Buffer;
+    let put = |k: &str, v: &str| EntryT::new(k.as_bytes().to_vec(), v.as_bytes().to_vec());
+    let del = |k: &str| EntryT::tombstone(k.as_bytes().to_vec());
+    let table = |entries: Vec<EntryT>| -> Arc<dyn MemTable> {
+        let table = Buffer::new();
+        for (seq, entry) in entries.into_iter().enumerate() {
+            table.put(entry, seq as u64);
+        }
+        Arc::new(table)
+    };
+    let newer = table(vec![put("b", "new"), del("c"), put("e", "new")]);
+    let older = table(vec![
+        put("a", "old"),
+        put("b", "old"),
+        put("c", "old"),
+        put("d", "old"),
+    ]);
+    //a write after the sequence number of the cursor is not seen
+    newer.put(put("a", "newest"), 3);
+    let (newer, older) = (MemCursor::new(newer, 3), MemCursor::new(older, 4));
+    let mut iter = TreeIter {
+        sources: vec![Box::new(newer), Box::new(older)],
+        start: Unbounded,
+        end: Unbounded,
+    };
+    let pair = |k: &str, v: &str| (k.as_bytes().to_vec(), v.as_bytes().to_vec());
+    let all: Vec<_> = iter.by_ref().map(|item| item.unwrap()).collect();
+    assert_eq!(
+        vec![
+            pair("a", "old"),
+            pair("b", "new"),
+            pair("d", "old"),
+            pair("e", "new")
+        ],
+        all
+    );
+    assert_eq!(pair("e", "new"), iter.prev().unwrap().unwrap());
+    assert_eq!(pair("d", "old"), iter.prev().unwrap().unwrap());
+    assert_eq!(pair("b", "new"), iter.prev().unwrap().unwrap());
+    //change direction
+    assert_eq!(pair("b", "new"), iter.next().unwrap().unwrap());
+    assert_eq!(pair("d", "old"), iter.next().unwrap().unwrap());
+
+    iter.start = Excluded(b"a".to_vec());
+    iter.end = Included(b"c".to_vec());
+    iter.seek(b"").unwrap();
+    assert_eq!(pair("b", "new"), iter.next().unwrap().unwrap());
+    assert!(iter.next().is_none());
+    iter.seek(b"z").unwrap();
+    assert_eq!(pair("b", "new"), iter.prev().unwrap().unwrap());
+    assert!(iter.prev().is_none());
+
+    iter.end = Unbounded;
+    let desc: Vec<_> = iter.into_rev().unwrap().map(|item| item.unwrap()).collect();
+    assert_eq!(
+        vec![pair("e", "new"), pair("d", "old"), pair("b", "new")],
+        desc
+    );
+}
@@ -1,24 +1,34 @@
 use crate::run;
 //use core::fmt::Alignment::Left;
 use std::collections::VecDeque;
+use std::sync::Arc;
 
 pub struct Level {
-    pub runs: VecDeque<run::Run>,
+    //shared with the iterators reading them, newest first
+    pub runs: VecDeque<Arc<run::Run>>,
     pub max_runs: usize,
-    pub max_run_size: usize,
+    //bytes of a run of the level
+    pub max_run_size: u64,
 }
 
 impl Level {
-    pub fn new(max_runs: usize, max_run_size: usize) -> Level {
+    pub fn new(max_runs: usize, max_run_size: u64) -> Level {
         Level {
             runs: VecDeque::new(),
-            max_runs: max_runs,
-            max_run_size: max_run_size,
+            max_runs,
+            max_run_size,
         }
     }
 
+    //runs the level can still take. it is also full once its runs hold the bytes of
+    //max_runs runs, which runs flushed from large buffers or write batches can reach first
     pub fn remaining(&self) -> usize {
-        self.max_runs - self.runs.len()
+        let bytes: u64 = self.runs.iter().map(|run| run.bytes).sum();
+        if bytes >= self.max_runs as u64 * self.max_run_size {
+            0
+        } else {
+            self.max_runs - self.runs.len()
+        }
     }
 }
 
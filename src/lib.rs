@@ -1,6 +1,17 @@
+pub mod batch;
 pub mod buffer;
+pub mod cache;
+pub mod coding;
 pub mod data_type;
+pub mod error;
+pub mod iterator;
 pub mod level;
+pub mod lock;
 pub mod lsm;
+pub mod manifest;
+pub mod memtable;
 pub mod merge;
+pub mod options;
 pub mod run;
+pub mod skiplist;
+pub mod wal;
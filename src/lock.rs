@@ -0,0 +1,56 @@
+//only one LSMTree at a time may use a data directory. it holds an advisory lock on the
+//LOCK file in the directory for as long as it is open. the lock belongs to the open file,
+//so it also keeps out a second tree in the same process, and the OS drops it if the
+//process dies.
+use crate::error::{Error, Result};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+pub static LOCK_FILE_NAME: &str = "LOCK";
+
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    /// Takes the lock on `path`, creating the file if needed. Fails with
+    /// `Error::AlreadyInUse` instead of waiting if someone else holds it.
+    pub fn lock(path: &Path) -> Result<FileLock> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Err(Error::AlreadyInUse(path.to_path_buf()));
+            }
+            return Err(e.into());
+        }
+        Ok(FileLock { file })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        //closing the file would release it as well
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+#[test]
+fn test_lock() {
+    use std::fs;
+    let _ = fs::create_dir_all("/tmp/lock_unit_test");
+    let path = Path::new("/tmp/lock_unit_test/LOCK");
+    let lock = FileLock::lock(path).unwrap();
+    assert!(matches!(FileLock::lock(path), Err(Error::AlreadyInUse(_))));
+    drop(lock);
+    let _lock = FileLock::lock(path).unwrap();
+}
@@ -1,22 +1,35 @@
-use crate::buffer;
-use crate::data_type::{EntryT, ValueT, ENTRY_SIZE, TOMBSTONE};
+use crate::batch::WriteBatch;
+use crate::cache::BlockCache;
+use crate::data_type::{EntryT, KeyT, ValueT, ENTRY_HEADER_SIZE};
+use crate::error::{Error, Result};
+use crate::iterator::{RangePage, RevTreeIter, TreeIter};
 use crate::level;
+use crate::lock;
+use crate::manifest;
+use crate::memtable::{MemTable, MemTableKind};
 use crate::merge;
+use crate::options::{Options, OPTIONS_FILE_NAME};
 use crate::run;
+use crate::wal;
+#[cfg(test)]
 use rand::{thread_rng, Rng};
-use std::{io, thread};
 //use bit_vec::Iter;
 //use rand::distributions::weighted::WeightedError::TooMany;
 //use std::borrow::Borrow;
+#[cfg(test)]
 use std::collections::HashMap;
+use std::collections::{HashSet, VecDeque};
 //use std::ptr::null;
 //use std::sync::{Arc, Mutex};
-use crate::data_type;
-use rand::distributions::UnitSphereSurface;
 use std::cmp::max;
 use std::fs::read_dir;
-use std::iter::Inspect;
+use std::mem;
+use std::ops::Bound;
+use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread;
 use std::time::{Duration, Instant};
 use std::{fs, str};
 
@@ -25,121 +38,242 @@ pub static DEFAULT_TREE_FANOUT: u64 = 10;
 pub static DEFAULT_BUFFER_NUM_PAGES: u64 = 1000;
 pub static DEFAULT_THREAD_COUNT: u64 = 4;
 pub static DEFAULT_BF_BITS_PER_ENTRY: f32 = 0.5;
+pub static DEFAULT_PREFIX_LEN: u64 = 0;
+pub static DEFAULT_BLOCK_CACHE_SIZE: u64 = 8 << 20;
+pub static DEFAULT_SLOWDOWN_WRITES_IMMUTABLES: u64 = 2;
+pub static DEFAULT_STOP_WRITES_IMMUTABLES: u64 = 4;
+pub static DEFAULT_MEMTABLE: MemTableKind = MemTableKind::SkipList;
+//how long a write is delayed while the background jobs fall behind
+static SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
 pub static DEFAULT_TREE_NAME: &str = "rust";
+pub static DEFAULT_SYNC_POLICY: wal::SyncPolicy = wal::SyncPolicy::NoSync;
 
+/// Per-write settings
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteOptions {
+    /// Overrides the sync policy of the tree for this write
+    pub sync_policy: Option<wal::SyncPolicy>,
+}
+
+/// Counters of a tree since it was opened
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Statistics {
+    /// Writes delayed because the background jobs fell behind
+    pub slowdown_writes: u64,
+    /// Writes that waited for a flush because too many full buffers were waiting
+    pub stopped_writes: u64,
+    /// Time writes spent delayed or waiting
+    pub stall_time: Duration,
+}
+
+//what a write has to do before it goes on
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WriteStall {
+    None,
+    Slowdown,
+    Stop,
+}
+
+//a full buffer waiting for its flush. until the flush is done its log is kept under
+//wal::sealed_log_name(number), and number is the file number of the run it becomes.
+struct Immutable {
+    number: u64,
+    buffer: Arc<dyn MemTable>,
+}
+
+//everything only writers touch. writes hold it until they are logged, so they are logged
+//one at a time. a single put or delete then goes into the buffer in parallel with others.
+struct WriteState {
+    //holds every write that is in the buffer but not yet in a run. writers sync it after
+    //dropping the writer lock, so it is shared with them
+    wal: Arc<wal::Wal>,
+    //used for writes that do not ask for their own sync policy
+    sync_policy: wal::SyncPolicy,
+    //keeps other trees out of path until close or drop, None once closed
+    lock: Option<lock::FileLock>,
+    //the sequence number of the next write, which orders the writes of a key in the buffer
+    sequence: u64,
+}
+
+//the part of the tree shared with the flushes and merges running on the worker pool
+struct TreeInner {
+    //readers only hold these locks while they copy out what they need. flushes and
+    //merges replace whole runs in levels and only after the runs are complete.
+    levels: RwLock<Vec<level::Level>>,
+    //writes hold the lock shared while they insert and a write batch holds it alone, so
+    //the buffer is only sealed once no insert into it is running
+    buffer: RwLock<Arc<dyn MemTable>>,
+    //full buffers waiting for their flush, newest first
+    immutables: RwLock<VecDeque<Arc<Immutable>>>,
+    //used for bloom filter initialization
+    bf_bits_per_entry: f32,
+    //directory holding the runs, the write-ahead logs and the manifest
+    path: PathBuf,
+    //the settings in use, including the ones read back from the OPTIONS file
+    options: Options,
+    //records which run files make up every level
+    manifest: Mutex<manifest::Manifest>,
+    next_file_number: AtomicU64,
+    //held by the flush or merge running, they run one at a time
+    background: Mutex<()>,
+    //the error of the first background job that failed. no writes are taken after it.
+    background_error: Mutex<Option<Error>>,
+    //signalled when a full buffer is flushed or a background job fails, which is what
+    //stopped writes wait for
+    flush_lock: Mutex<()>,
+    flush_done: Condvar,
+    //pages of the runs, possibly shared with other trees
+    block_cache: Option<Arc<BlockCache>>,
+}
+
+/// A LSM tree based key value store. Every method takes `&self`, so the tree can be
+/// shared between threads, e.g. in an `Arc`. Writes are logged one at a time, then puts
+/// and deletes go into the buffer in parallel if the memtable allows it.
+///
+/// A full buffer is flushed by a background job on the worker pool, which also merges
+/// levels that are full. Reads see the full buffer until its run is in level 0.
 pub struct LSMTree {
-    levels: Vec<level::Level>,
-    buffer: buffer::Buffer,
+    inner: Arc<TreeInner>,
+    writer: Mutex<WriteState>,
+    //runs the flushes and merges
     worker_pool: threadpool::ThreadPool,
-    bf_bits_per_entry: f32,
-    //used for bloom filter initialization
-    depth: u64,
-    tree_name: String,
+    //syncs the log after writes under SyncPolicy::Interval once the tree goes idle
+    sync_timer: wal::SyncTimer,
+    //for statistics, stall_micros is in microseconds
+    slowdown_writes: AtomicU64,
+    stopped_writes: AtomicU64,
+    stall_micros: AtomicU64,
 }
 
-impl LSMTree {
-    /// Returns a LSM tree based key value store
-    ///
-    /// # Arguments
-    ///
-    /// * `buf_max_entries` - Max number of entries in memory buffer
-    /// * `dep` - depth of LSM tree
-    /// * `fanout` - A factor that determines how to scale Run size for deeper levels
-    /// * `bf_bits_per_entry` - Used for bloom filter size initialization
-    /// * `num_threads` - Used for thread pool initialization
-    ///
-    /// # Example
-    ///
-    /// ```
-    ///
-    /// use lsm_kv::lsm;
-    /// let mut lsm = lsm::LSMTree::new(100, 5, 10, 0.5, 4, "doc_test".to_string());
-    /// lsm.put("hello", "world");
-    /// lsm.put("facebook", "google");
-    /// lsm.put("amazon", "linkedin");
-    /// assert_eq!(lsm.get("hello"), Some("world".to_string()));
-    /// assert_eq!(lsm.get("facebook"), Some("google".to_string()));
-    /// lsm.del("hello");
-    /// assert_eq!(lsm.get("hello"), None);
-    /// lsm.range("amazon", "facebook");
-    /// lsm.close();
-    /// let mut lsm2 = lsm::LSMTree::new(100, 5, 10, 0.5, 4, "doc_test".to_string());
-    /// lsm2.load();
-    /// assert_eq!(lsm2.get("hello"), None);
-    /// assert_eq!(lsm2.get("facebook"), Some("google".to_string()));
-    ///
-    /// ```
-    //TODO after loading the read will fail due to bloom filter problem
-    pub fn new(
-        buf_max_entries: u64,
-        dep: u64,
-        fanout: u64,
-        bf_bits_per_entry: f32,
-        num_threads: u64,
-        tree_name: String,
-    ) -> LSMTree {
-        let mut max_run_size = buf_max_entries;
-        let mut tmp_levels: Vec<level::Level> = Vec::new();
-        let mut tmp_deps = dep;
-        //create a directory for store files on disk
-        fs::create_dir(format!("/tmp/{}/", tree_name));
-        while tmp_deps > 0 {
-            //level id starts from 0 to depth-1
-            fs::create_dir(format!(
-                "/tmp/{}/{}/",
-                tree_name,
-                tmp_levels.len().to_string()
-            ));
-            tmp_levels.push(level::Level::new(fanout as usize, max_run_size as usize));
-            //create a subdir for corresponding level
-            max_run_size *= fanout;
-            tmp_deps -= 1;
-        }
-
-        LSMTree {
-            levels: tmp_levels,
-            depth: dep,
-            bf_bits_per_entry: bf_bits_per_entry,
-            worker_pool: threadpool::ThreadPool::new(num_threads as usize),
-            buffer: buffer::Buffer::new(buf_max_entries as usize),
-            tree_name: tree_name,
-        }
-    }
-
-    pub fn get_run(&mut self, mut run_id: usize) -> Option<&mut run::Run> {
-        for level in &mut self.levels {
-            //println!("level len : {}", level.runs.len());
-            if run_id < level.runs.len() {
-                //println!("get run {}", run_id);
-                return level.runs.get_mut(run_id);
-            } else {
-                run_id -= level.runs.len();
-            }
+impl TreeInner {
+    //a new run of level for `entries` entries, reading through the block cache of the tree
+    fn new_run(&self, level: usize, file_number: u64, entries: usize) -> run::Run {
+        let mut run = run::Run::new(
+            entries as u64,
+            self.bf_bits_per_entry,
+            self.options.prefix_len as usize,
+            &self.path,
+            level,
+            file_number,
+        );
+        run.block_cache = self.block_cache.clone();
+        run
+    }
+
+    fn new_file_number(&self) -> u64 {
+        self.next_file_number.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn log_edit(
+        &self,
+        added: Vec<(usize, manifest::RunMeta)>,
+        removed: Vec<(usize, u64)>,
+    ) -> Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let edit = manifest::VersionEdit {
+            next_file_number: self.next_file_number.load(Ordering::SeqCst),
+            added,
+            removed,
+        };
+        manifest.log(&edit)
+    }
+
+    //every run of the tree, newest first. merges may replace runs right after, but the
+    //runs returned stay readable for as long as they are held.
+    fn runs(&self) -> Vec<Arc<run::Run>> {
+        self.levels
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|level| level.runs.iter().cloned())
+            .collect()
+    }
+
+    //the buffer and every full buffer, newest first, each with the sequence number its
+    //reads stop at. only the writes put so far are seen, and the reads copy nothing ahead.
+    fn memtables(&self) -> Vec<(Arc<dyn MemTable>, u64)> {
+        //the buffer before the full buffers and those before the runs, like get
+        let buffer = Arc::clone(&self.buffer.read().unwrap());
+        let seq = buffer.sequence();
+        let mut res = vec![(buffer, seq)];
+        for immutable in self.immutables.read().unwrap().iter() {
+            let seq = immutable.buffer.sequence();
+            res.push((Arc::clone(&immutable.buffer), seq));
         }
-        None
+        res
     }
 
-    pub fn num_runs(&self) -> usize {
-        let mut res: usize = 0;
-        for level in self.levels.iter() {
-            res += level.runs.len();
+    fn check_background_error(&self) -> Result<()> {
+        match &*self.background_error.lock().unwrap() {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    //flush every full buffer, oldest first, merging levels down where needed. the first
+    //error is kept, and every later call returns it without doing anything.
+    fn background_work(&self) -> Result<()> {
+        let _running = self.background.lock().unwrap();
+        self.check_background_error()?;
+        let res = self.flush_immutables();
+        if let Err(e) = &res {
+            *self.background_error.lock().unwrap() = Some(e.clone());
+            //stopped writes wait for nothing now
+            self.notify_flush_done();
         }
         res
     }
 
+    fn notify_flush_done(&self) {
+        let _flush_lock = self.flush_lock.lock().unwrap();
+        self.flush_done.notify_all();
+    }
+
+    //writes slow down once slowdown_writes_immutables full buffers wait for their flush,
+    //or once more of them wait than level 0 has room for, so that a merge has to run
+    //first. they stop at stop_writes_immutables. deeper levels need no check of their own:
+    //their merges run right before the flush that needs the room, so a merge falling
+    //behind keeps the full buffers waiting.
+    fn write_stall(&self) -> WriteStall {
+        let immutables = self.immutables.read().unwrap().len() as u64;
+        if immutables >= self.options.stop_writes_immutables {
+            return WriteStall::Stop;
+        }
+        let level0_room = self.levels.read().unwrap()[0].remaining() as u64;
+        if immutables >= self.options.slowdown_writes_immutables || immutables > level0_room {
+            WriteStall::Slowdown
+        } else {
+            WriteStall::None
+        }
+    }
+
+    fn flush_immutables(&self) -> Result<()> {
+        loop {
+            let oldest = match self.immutables.read().unwrap().back() {
+                Some(immutable) => immutable.clone(),
+                None => return Ok(()),
+            };
+            /*
+             * flush level 0 if necessary
+             * to create space
+             */
+            self.merge_down(0)?;
+            self.flush_immutable(&oldest)?;
+        }
+    }
+
     //compact level i data to level i+1
-    fn merge_down(&mut self, current: usize) {
+    fn merge_down(&self, current: usize) -> Result<()> {
         let mut merge_ctx: merge::MergeContextT = merge::MergeContextT::new();
-        let mut entry: EntryT;
         let next: usize;
+        let depth = self.levels.read().unwrap().len();
         //assert!(current >= self.levels.iter());
-        if self.levels[current].remaining() > 0 {
+        if self.levels.read().unwrap()[current].remaining() > 0 {
             //no need for compaction and merge down
-            return;
-        } else if current == self.levels.len() - 1 {
+            return Ok(());
+        } else if current == depth - 1 {
             //can not merge down anymore
-            println!("No more space in tree");
-            return;
+            return Err(Error::NoSpace);
         } else {
             next = current + 1;
         }
@@ -148,343 +282,1909 @@ impl LSMTree {
          * If the next level does not have space for the current level,
          * recursively merge the next level downwards to create some
          */
-        if self.levels[next].remaining() == 0 {
-            self.merge_down(next);
+        if self.levels.read().unwrap()[next].remaining() == 0 {
+            self.merge_down(next)?;
             //ensure that after merge down, level next has free space now.
-            assert!(self.levels[next].remaining() > 0)
+            assert!(self.levels.read().unwrap()[next].remaining() > 0)
         }
 
         /*
          * Merge all runs in the current level into the first
          * run in the next level
          */
-        for run in self.levels[current].runs.iter_mut() {
+        //only background jobs change levels and they run one at a time, so the inputs and
+        //the emptiness of level next stay as they are until the merged run replaces them.
+        //readers go on reading the inputs meanwhile.
+        let (inputs, drop_tombstones) = {
+            let levels = self.levels.read().unwrap();
+            let inputs: Vec<Arc<run::Run>> = levels[current].runs.iter().cloned().collect();
+            //tombstones have nothing left to hide once nothing older is below them
+            (inputs, next == depth - 1 && levels[next].runs.is_empty())
+        };
+        //the merged run can not be larger than all the runs it is built from
+        let mut capacity: u64 = 0;
+        let mut entries: usize = 0;
+        for run in inputs.iter() {
             //add all entries in current levels for merging
-            merge_ctx.add(run.map_read_default(), run.size as usize);
+            merge_ctx.add(run.map_read_default()?, run.size as usize);
+            capacity += run.bytes;
+            entries += run.size as usize;
         }
-        let size = self.levels[next].max_run_size as u64;
-        let id = self.levels[next].runs.len();
-        self.levels[next].runs.push_front(run::Run::new(
-            size,
-            self.bf_bits_per_entry,
-            &self.tree_name,
-            next,
-            id,
-        ));
+        let file_number = self.new_file_number();
+        //the new run only joins the level once the manifest knows about it
+        let mut merged = self.new_run(next, file_number, entries);
         //start writing back this compacted run in next level to a new file on disk
-        self.levels[next].runs[0].map_write();
+        merged.map_write(capacity)?;
         //merge_ctx.print();
-        while !merge_ctx.done() {
-            entry = merge_ctx.next();
-            //TODO merge_ctx.next() return a wrong value.
+        for entry in merge_ctx {
             //println!("{}", str::from_utf8(&entry.value).unwrap());
-            if !(next == self.levels.len() - 1 && entry.value == TOMBSTONE.as_bytes().to_vec()) {
-                self.levels[next].runs[0].put(&entry);
+            if !(drop_tombstones && entry.is_tombstone()) {
+                merged.put(&entry);
             }
         }
-        self.levels[next].runs[0].unmap();
+        merged.unmap()?;
         //finish writing back for compacted run
 
-        //unmap the old runs and clear these files
-        for run in self.levels[current].runs.iter_mut() {
-            run.unmap();
+        //the new run replaces the old ones in a single manifest edit
+        let added = vec![(next, merged.meta())];
+        let removed = inputs
+            .iter()
+            .map(|run| (current, run.file_number))
+            .collect();
+        self.log_edit(added, removed)?;
+        {
+            //readers see either the old runs or the merged one, never both or neither
+            let mut levels = self.levels.write().unwrap();
+            levels[next].runs.push_front(Arc::new(merged));
+            levels[current].runs.clear();
         }
-        self.levels[current].runs.clear();
+
+        //clear the files of the old runs. readers still holding one keep its mapping
+        for run in inputs {
+            //the manifest no longer knows the file, a later load removes it if this fails
+            let _ = fs::remove_file(&run.tmp_file);
+        }
+        Ok(())
     }
 
-    fn fill_str_with_witespace(&self, input: &str, length: usize) -> Vec<u8> {
-        let mut res = vec![' ' as u8; length - input.len()];
-        res.extend(input.as_bytes().to_vec());
-        //assert_eq!(res.len(), length);
-        res
+    //write a full buffer to a new run in level 0
+    fn flush_immutable(&self, immutable: &Immutable) -> Result<()> {
+        let entries: Vec<EntryT> = immutable.buffer.iter().collect();
+        let mut flushed = self.new_run(0, immutable.number, entries.len());
+        let capacity: usize = entries.iter().map(EntryT::encoded_len).sum();
+        flushed.map_write(capacity as u64)?;
+        for entry_in_buf in entries.iter() {
+            flushed.put(entry_in_buf);
+        }
+        flushed.unmap()?;
+        self.log_edit(vec![(0, flushed.meta())], vec![])?;
+        //the log goes once the manifest knows the run, and before a merge can take the run
+        //away. so a log left by a crash belongs to a flushed buffer if and only if the
+        //manifest has the run of its number.
+        fs::remove_file(self.path.join(wal::sealed_log_name(immutable.number)))?;
+        //the run joins level 0 before the full buffer goes. readers look at the full
+        //buffers first, so they find the flushed entries in one place or the other.
+        self.levels.write().unwrap()[0]
+            .runs
+            .push_front(Arc::new(flushed));
+        self.immutables.write().unwrap().pop_back();
+        self.notify_flush_done();
+        Ok(())
+    }
+}
+
+impl LSMTree {
+    /// Returns a LSM tree based key value store stored in `/tmp/<tree_name>`, loading
+    /// whatever the tree already holds like open does
+    ///
+    /// # Arguments
+    ///
+    /// * `tree_name` - name of the directory under `/tmp` holding the tree
+    /// * `options` - settings of the tree, see `Options::builder`. A tree that already
+    ///   exists keeps the settings it was created with.
+    ///
+    /// # Example
+    ///
+    /// ```
+    ///
+    /// use lsm_kv::lsm;
+    /// use lsm_kv::options::Options;
+    /// let options = Options::builder()
+    ///     .write_buffer_size(4096)
+    ///     .depth(5)
+    ///     .fanout(10)
+    ///     .build()
+    ///     .unwrap();
+    /// # let _ = std::fs::remove_dir_all("/tmp/doc_test");
+    /// let lsm = lsm::LSMTree::new("doc_test", options)?;
+    /// lsm.put("hello", "world")?;
+    /// lsm.put("facebook", "google")?;
+    /// lsm.put("amazon", "linkedin")?;
+    /// assert_eq!(lsm.get("hello")?, Some("world".to_string()));
+    /// assert_eq!(lsm.get("facebook")?, Some("google".to_string()));
+    /// lsm.del("hello")?;
+    /// assert_eq!(lsm.get("hello")?, None);
+    /// lsm.range("amazon", "facebook")?;
+    /// lsm.close()?;
+    /// let lsm2 = lsm::LSMTree::new("doc_test", options)?;
+    /// assert_eq!(lsm2.get("hello")?, None);
+    /// assert_eq!(lsm2.get("facebook")?, Some("google".to_string()));
+    /// # Ok::<(), lsm_kv::error::Error>(())
+    /// ```
+    pub fn new(tree_name: &str, options: Options) -> Result<LSMTree> {
+        LSMTree::open(Path::new("/tmp").join(tree_name), options)
+    }
+
+    /// Opens the tree stored in the directory `path`, creating the directory if needed,
+    /// and loads whatever it already holds. Every file of the tree (runs, write-ahead log
+    /// and manifest) lives under `path`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::lsm::LSMTree;
+    /// use lsm_kv::options::Options;
+    /// # let _ = std::fs::remove_dir_all("/tmp/doc_open_test");
+    /// let lsm = LSMTree::open("/tmp/doc_open_test/data", Options::default())?;
+    /// lsm.put("hello", "world")?;
+    /// lsm.close()?;
+    /// let lsm2 = LSMTree::open("/tmp/doc_open_test/data", Options::default())?;
+    /// assert_eq!(lsm2.get("hello")?, Some("world".to_string()));
+    /// # Ok::<(), lsm_kv::error::Error>(())
+    /// ```
+    pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<LSMTree> {
+        let (tree, state) = LSMTree::create(path.as_ref().to_path_buf(), options, None)?;
+        tree.load(state)?;
+        Ok(tree)
+    }
+
+    /// Like open, but the runs of the tree read through `block_cache`, which other trees
+    /// may share, instead of a cache of `options.block_cache_size` bytes of their own.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::cache::BlockCache;
+    /// use lsm_kv::lsm::LSMTree;
+    /// use lsm_kv::options::Options;
+    /// use std::sync::Arc;
+    /// # let _ = std::fs::remove_dir_all("/tmp/doc_cache_test");
+    /// let cache = Arc::new(BlockCache::new(64 << 20));
+    /// let users = LSMTree::open_with_cache("/tmp/doc_cache_test/users", Options::default(), cache.clone())?;
+    /// let items = LSMTree::open_with_cache("/tmp/doc_cache_test/items", Options::default(), cache.clone())?;
+    /// # Ok::<(), lsm_kv::error::Error>(())
+    /// ```
+    pub fn open_with_cache<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+        block_cache: Arc<BlockCache>,
+    ) -> Result<LSMTree> {
+        let (tree, state) =
+            LSMTree::create(path.as_ref().to_path_buf(), options, Some(block_cache))?;
+        tree.load(state)?;
+        Ok(tree)
+    }
+
+    //set up the directories and logs of a tree in path, without loading its runs. without
+    //a block cache to share, the tree gets its own one as big as the options ask for. the
+    //replayed manifest is returned for load, so the log is only read once.
+    fn create(
+        path: PathBuf,
+        options: Options,
+        block_cache: Option<Arc<BlockCache>>,
+    ) -> Result<(LSMTree, manifest::ManifestState)> {
+        //create a directory for store files on disk
+        fs::create_dir_all(&path)?;
+        //nothing in path may be touched before the lock is held
+        let lock = lock::FileLock::lock(&path.join(lock::LOCK_FILE_NAME))?;
+        //an existing tree keeps the shape it was created with
+        let options = options.load_or_save(&path)?;
+        let mut max_run_size = options.write_buffer_size;
+        let mut tmp_levels: Vec<level::Level> = Vec::new();
+        for level in 0..options.depth {
+            //level id starts from 0 to depth-1, every level gets a subdir
+            fs::create_dir_all(path.join(level.to_string()))?;
+            tmp_levels.push(level::Level::new(options.fanout as usize, max_run_size));
+            max_run_size *= options.fanout;
+        }
+
+        let wal = Arc::new(wal::Wal::open(&path.join(wal::WAL_FILE_NAME))?);
+        let mut manifest = manifest::Manifest::open(&path.join(manifest::MANIFEST_FILE_NAME))?;
+        let manifest_state = manifest.replay()?;
+        let block_cache = block_cache.or_else(|| {
+            if options.block_cache_size > 0 {
+                Some(Arc::new(BlockCache::new(options.block_cache_size as usize)))
+            } else {
+                None
+            }
+        });
+
+        let tree = LSMTree {
+            writer: Mutex::new(WriteState {
+                wal,
+                sync_policy: options.sync_policy,
+                lock: Some(lock),
+                sequence: 0,
+            }),
+            worker_pool: threadpool::ThreadPool::new(options.num_threads as usize),
+            sync_timer: wal::SyncTimer::new(),
+            slowdown_writes: AtomicU64::new(0),
+            stopped_writes: AtomicU64::new(0),
+            stall_micros: AtomicU64::new(0),
+            inner: Arc::new(TreeInner {
+                levels: RwLock::new(tmp_levels),
+                buffer: RwLock::new(options.memtable.new_memtable().into()),
+                immutables: RwLock::new(VecDeque::new()),
+                bf_bits_per_entry: options.bf_bits_per_entry,
+                path,
+                options,
+                manifest: Mutex::new(manifest),
+                //never reuse the file of a run that a later load could still see
+                next_file_number: AtomicU64::new(manifest_state.next_file_number),
+                background: Mutex::new(()),
+                background_error: Mutex::new(None),
+                flush_lock: Mutex::new(()),
+                flush_done: Condvar::new(),
+                block_cache,
+            }),
+        };
+        Ok((tree, manifest_state))
+    }
+
+    /// The block cache the runs of the tree read through, if it has one
+    pub fn block_cache(&self) -> Option<&Arc<BlockCache>> {
+        self.inner.block_cache.as_ref()
+    }
+
+    //takes the writer state, which every write needs
+    fn writer(&self) -> MutexGuard<'_, WriteState> {
+        self.writer.lock().unwrap()
+    }
+
+    /// Sets how writes are synced to the write-ahead log unless they carry their own policy
+    pub fn set_sync_policy(&self, policy: wal::SyncPolicy) {
+        self.writer().sync_policy = policy;
+    }
+
+    /// The settings this tree runs with
+    pub fn options(&self) -> &Options {
+        &self.inner.options
     }
 
-    fn vec_u8_to_str(&self, input: &Vec<u8>) -> String {
-        let res: String = str::from_utf8(input).unwrap().trim().to_owned();
+    /// The directory holding every file of this tree
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    pub fn get_run(&self, mut run_id: usize) -> Option<Arc<run::Run>> {
+        for level in self.inner.levels.read().unwrap().iter() {
+            //println!("level len : {}", level.runs.len());
+            if run_id < level.runs.len() {
+                //println!("get run {}", run_id);
+                return level.runs.get(run_id).cloned();
+            } else {
+                run_id -= level.runs.len();
+            }
+        }
+        None
+    }
+
+    pub fn num_runs(&self) -> usize {
+        let mut res: usize = 0;
+        for level in self.inner.levels.read().unwrap().iter() {
+            res += level.runs.len();
+        }
         res
     }
 
-    pub fn put(&mut self, key_str: &str, value_str: &str) -> bool {
-        let key = self.fill_str_with_witespace(key_str, data_type::KEY_SIZE);
-        let value = self.fill_str_with_witespace(value_str, data_type::VALUE_SIZE);
-        if self.buffer.full() == false {
-            //put to buffer success
-            self.buffer.put(key, value);
-            return true;
-        } else {
-            /*
-             * If the buffer is full, flush level 0 if necessary
-             * to create space
-             */
-            //println!("start merge");
-            self.merge_down(0);
+    //values are stored as raw bytes. Bytes that are not valid UTF-8 are replaced instead of panicking.
+    fn vec_u8_to_str(&self, input: &[u8]) -> String {
+        String::from_utf8_lossy(input).into_owned()
+    }
+
+    pub fn put(&self, key_str: &str, value_str: &str) -> Result<()> {
+        self.put_bytes(key_str.as_bytes(), value_str.as_bytes())
+    }
 
+    /// Inserts or overwrites `key` with `value`. Both are kept exactly as given,
+    /// so they may hold arbitrary bytes.
+    pub fn put_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_with_options(key, value, &WriteOptions::default())
+    }
+
+    pub fn put_with_options(&self, key: &[u8], value: &[u8], options: &WriteOptions) -> Result<()> {
+        check_write_size(ENTRY_HEADER_SIZE + key.len() + value.len())?;
+        self.write_entries(vec![EntryT::new(key.to_vec(), value.to_vec())], options)
+    }
+
+    /// Applies every put and delete of `batch` at once: readers and a crash see either
+    /// all of them or none. A batch above 4 GiB encoded fails with `Error::InvalidArgument`.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.write_with_options(batch, &WriteOptions::default())
+    }
+
+    pub fn write_with_options(&self, batch: WriteBatch, options: &WriteOptions) -> Result<()> {
+        check_write_size(batch.entries.iter().map(EntryT::encoded_len).sum())?;
+        self.write_entries(batch.entries, options)
+    }
+
+    //log the entries as one record to the write-ahead log, then apply them to the buffer.
+    //a batch holds the buffer alone, so its entries are never seen apart, and no write is
+    //split by a flush. if the entries could not be logged, the buffer is left untouched.
+    fn write_entries(&self, entries: Vec<EntryT>, options: &WriteOptions) -> Result<()> {
+        //the stall runs before the writer lock is taken, so stalled writers do not keep
+        //each other or close out. the writers that got the lock first may have sealed
+        //more buffers meanwhile, so a stop is checked again under the lock.
+        let mut state = loop {
+            self.stall_write()?;
+            let state = self.writer();
+            if state.lock.is_none() {
+                return Err(Error::Closed);
+            }
+            //a tree whose flush failed would only pile up full buffers
+            self.inner.check_background_error()?;
+            if self.inner.write_stall() != WriteStall::Stop {
+                break state;
+            }
+        };
+        let buffer_size = self.inner.buffer.read().unwrap().approximate_memory_usage();
+        if buffer_size as u64 >= self.inner.options.write_buffer_size {
             /*
-             * Flush the buffer to level 0.
+             * If the buffer is full, hand it to a background job
+             * to flush it to level 0, and go on with a new buffer and log
              */
-            let size = self.levels[0].max_run_size as u64;
-            let id = self.levels[0].runs.len();
-            self.levels[0].runs.push_front(run::Run::new(
-                size,
-                self.bf_bits_per_entry,
-                &self.tree_name,
-                0,
-                id,
-            ));
-            self.levels[0].runs[0].map_write();
-
-            for entry_in_buf in self.buffer.entries.iter() {
-                self.levels[0].runs[0].put(&entry_in_buf);
+            self.seal_buffer(&mut state)?;
+            self.schedule_background_work();
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let policy = options.sync_policy.unwrap_or(state.sync_policy);
+        //the fsync waits until the writer lock is dropped, so the writers queued behind
+        //this one can append their records meanwhile and share it
+        let target = state.wal.write_record(&entries, policy)?;
+        if let (None, wal::SyncPolicy::Interval(interval)) = (target, policy) {
+            //no later write may come to sync the record
+            self.sync_timer
+                .schedule(&state.wal, Instant::now() + interval);
+        }
+        let sync = target.map(|target| (Arc::clone(&state.wal), target));
+        let seq = state.sequence;
+        state.sequence += entries.len() as u64;
+        //the buffer lock is taken before the next writer can seal the buffer, and the
+        //sequence numbers keep the order of the log whatever order the inserts land in
+        if entries.len() == 1 {
+            let buffer = self.inner.buffer.read().unwrap();
+            drop(state);
+            buffer.put(entries.into_iter().next().unwrap(), seq);
+        } else {
+            #[allow(clippy::readonly_write_lock)]
+            let buffer = self.inner.buffer.write().unwrap();
+            drop(state);
+            for (i, entry) in entries.into_iter().enumerate() {
+                buffer.put(entry, seq + i as u64);
             }
-            self.levels[0].runs[0].unmap();
-
-            //buffer already written to levels.front().runs.front(). We can clear it now for inserting new entry.
-            self.buffer.empty();
-            self.buffer.put(key, value);
-            true
         }
+        //the entries are readable before they are synced, but the write only returns once
+        //they are. the old log stays open here even if a seal renamed it meanwhile.
+        if let Some((wal, target)) = sync {
+            wal.sync_to(target)?;
+        }
+        Ok(())
     }
 
-    pub fn get(&mut self, key_str: &str) -> Option<String> {
-        let key = self.fill_str_with_witespace(key_str, data_type::KEY_SIZE);
-        let res: String;
-        //read from buffer first. then from level 0 to max_level. return first match entry.
-        let mut latest_val: ValueT = ValueT::new();
-        let mut latest_run: i32 = -1;
-        //multi threading searching on multiple Runs is not available for now
-        match self.buffer.get(&key) {
-            Some(v) => {
-                //found in buffer, return the result;
-                res = self.vec_u8_to_str(&v);
-                if res != TOMBSTONE.to_string() {
-                    return Some(res);
-                } else {
-                    return None;
-                }
+    //delay or block the write while the background jobs fall behind
+    fn stall_write(&self) -> Result<()> {
+        let inner = &self.inner;
+        let started = Instant::now();
+        match inner.write_stall() {
+            WriteStall::None => return Ok(()),
+            WriteStall::Slowdown => {
+                thread::sleep(SLOWDOWN_DELAY);
+                self.slowdown_writes.fetch_add(1, Ordering::Relaxed);
             }
-            _ => {
-                //not found in buffer, start searching in vector<Level>
-                //println!("key {} not found in buffer", str::from_utf8(&key).unwrap());
-                for current_run in 0..self.num_runs() as u64 {
-                    let current_val: ValueT;
-                    // println!(
-                    //     "search in Run {}, latest_run is {}",
-                    //     current_run, latest_run
-                    // );
-                    //let mut run: run::Run;
-                    if latest_run >= 0 || (self.get_run(current_run as usize).is_none()) {
-                        // Stop search if we discovered a key in another run, or
-                        // if there are no more runs to search
-                        break;
-                    } else {
-                        let run = self.get_run(current_run as usize).unwrap();
-                        if run.get(&key).is_none() {
-                            // Couldn't find the key in the current run, so we need
-                            // to keep searching.
-                            //search();
-                            // println!(
-                            //     "key {} not found in Run {}",
-                            //     str::from_utf8(&key).unwrap(),
-                            //     current_run
-                            // );
-                        } else {
-                            // Update val if the run is more recent than the
-                            // last, then stop searching since there's no need
-                            // to search later runs.
-                            current_val = run.get(&key).unwrap();
-                            if latest_run < 0 || current_run < latest_run as u64 {
-                                latest_run = current_run as i32;
-                                latest_val = current_val;
-                            }
-                            break; //find the newest entry and break the for loop.
-                        }
-                    }
-                }
-
-                if latest_run >= 0 {
-                    res = self.vec_u8_to_str(&latest_val);
-                    if res != TOMBSTONE.to_string() {
-                        return Some(res);
-                    }
+            WriteStall::Stop => {
+                let mut flush_lock = inner.flush_lock.lock().unwrap();
+                while inner.write_stall() == WriteStall::Stop {
+                    inner.check_background_error()?;
+                    flush_lock = inner.flush_done.wait(flush_lock).unwrap();
                 }
+                self.stopped_writes.fetch_add(1, Ordering::Relaxed);
             }
         }
-        None
+        self.stall_micros
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Counters of this tree since it was opened
+    pub fn statistics(&self) -> Statistics {
+        Statistics {
+            slowdown_writes: self.slowdown_writes.load(Ordering::Relaxed),
+            stopped_writes: self.stopped_writes.load(Ordering::Relaxed),
+            stall_time: Duration::from_micros(self.stall_micros.load(Ordering::Relaxed)),
+        }
+    }
+
+    //turn the buffer into a full buffer waiting for its flush, and start a new one with
+    //a new log. the log of the full buffer is kept until its run is in level 0.
+    fn seal_buffer(&self, state: &mut WriteState) -> Result<()> {
+        let number = self.inner.new_file_number();
+        let log = self.inner.path.join(wal::WAL_FILE_NAME);
+        fs::rename(&log, self.inner.path.join(wal::sealed_log_name(number)))?;
+        state.wal = Arc::new(wal::Wal::open(&log)?);
+        //readers look at the buffer before the full buffers, and both change at once, so
+        //they always find the entries in one of them
+        let mut buffer = self.inner.buffer.write().unwrap();
+        let mut immutables = self.inner.immutables.write().unwrap();
+        let full = mem::replace(
+            &mut *buffer,
+            self.inner.options.memtable.new_memtable().into(),
+        );
+        immutables.push_front(Arc::new(Immutable {
+            number,
+            buffer: full,
+        }));
+        Ok(())
+    }
+
+    //flush the full buffers on the worker pool
+    fn schedule_background_work(&self) {
+        let inner = self.inner.clone();
+        self.worker_pool.execute(move || {
+            //the error is kept by the tree and returned to the next write
+            let _ = inner.background_work();
+        });
     }
 
-    pub fn range(&mut self, start_str: &str, end_str: &str) -> Vec<String> {
-        let start = self.fill_str_with_witespace(start_str, data_type::KEY_SIZE);
-        let end = self.fill_str_with_witespace(end_str, data_type::KEY_SIZE);
-        let mut buffer_range: Vec<String> = Vec::new(); //this is return value list
-        if end < start {
-            //invalid input
-            return buffer_range;
-        }
-        let mut ranges: HashMap<usize, Vec<EntryT>> = HashMap::new(); //record candidates in each level.
-        let mut merge_ctx = merge::MergeContextT::new();
-        let mut entry: EntryT;
-        //search in buffer and record result
-        ranges.insert(0, self.buffer.range(&start, &end));
-
-        for current_run in 0..self.depth {
-            match self.get_run(current_run as usize) {
-                Some(r) => {
-                    //start and end are used multiple times which causes "use of moved value"
-                    ranges.insert((current_run + 1) as usize, r.range(&start, &end));
+    /// Waits until the background jobs started so far are done, so every full buffer is
+    /// flushed. Returns the error of a background job that failed.
+    pub fn wait_for_background_work(&self) -> Result<()> {
+        self.worker_pool.join();
+        self.inner.check_background_error()
+    }
+
+    pub fn get(&self, key_str: &str) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key_str.as_bytes())?
+            .map(|val| self.vec_u8_to_str(&val)))
+    }
+
+    /// Returns the latest value of `key`, or None if it was never put or has been deleted.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<ValueT>> {
+        let key: KeyT = key.to_vec();
+        //read from buffer first. then from level 0 to max_level. return first match entry.
+        //the runs are only taken after the buffers, so a flush in between can not hide the key
+        let mut latest_val: Option<EntryT> = self.inner.buffer.read().unwrap().get(&key);
+        if latest_val.is_none() {
+            //full buffers waiting for their flush, newest first
+            latest_val = self
+                .inner
+                .immutables
+                .read()
+                .unwrap()
+                .iter()
+                .find_map(|immutable| immutable.buffer.get(&key));
+        }
+        if latest_val.is_none() {
+            //not found in buffer, start searching in vector<Level>
+            //println!("key {} not found in buffer", str::from_utf8(&key).unwrap());
+            for run in self.inner.runs() {
+                // Runs are ordered from the newest to the oldest, so the first
+                // run holding the key has its latest value and there is no
+                // need to search later runs.
+                latest_val = run.get(&key)?;
+                if latest_val.is_some() {
+                    break;
                 }
-                _ => {}
             }
         }
 
-        for kv in ranges.iter() {
-            //TODO is to_vec() a good option????
-            merge_ctx.add(kv.1.to_vec(), kv.1.len());
+        Ok(latest_val
+            .filter(|entry| !entry.is_tombstone())
+            .map(|entry| entry.value))
+    }
+
+    pub fn range(&self, start_str: &str, end_str: &str) -> Result<Vec<String>> {
+        Ok(self
+            .range_bytes(start_str.as_bytes(), end_str.as_bytes())?
+            .iter()
+            .map(|val| self.vec_u8_to_str(val))
+            .collect())
+    }
+
+    /// Returns the values of all live keys in `start..=end`, ordered by key.
+    pub fn range_bytes(&self, start: &[u8], end: &[u8]) -> Result<Vec<ValueT>> {
+        //every run of every level takes part, newest values win
+        self.range_iter(start, end)?
+            .map(|item| item.map(|(_, value)| value))
+            .collect()
+    }
+
+    /// Returns an iterator over every live key of the tree and its value, in key order.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::lsm::LSMTree;
+    /// use lsm_kv::options::Options;
+    /// # let _ = std::fs::remove_dir_all("/tmp/doc_iter_test");
+    /// let lsm = LSMTree::open("/tmp/doc_iter_test", Options::default())?;
+    /// lsm.put("b", "2")?;
+    /// lsm.put("a", "1")?;
+    /// lsm.put("c", "3")?;
+    /// lsm.del("c")?;
+    /// let mut iter = lsm.iter()?;
+    /// assert_eq!(Some((b"a".to_vec(), b"1".to_vec())), iter.next().transpose()?);
+    /// assert_eq!(Some((b"b".to_vec(), b"2".to_vec())), iter.next().transpose()?);
+    /// assert!(iter.next().is_none());
+    /// assert_eq!(Some((b"b".to_vec(), b"2".to_vec())), iter.prev().transpose()?);
+    /// iter.seek(b"a")?;
+    /// assert_eq!(Some((b"a".to_vec(), b"1".to_vec())), iter.next().transpose()?);
+    /// # Ok::<(), lsm_kv::error::Error>(())
+    /// ```
+    pub fn iter(&self) -> Result<TreeIter> {
+        self.range_iter_bounds(Unbounded, Unbounded)
+    }
+
+    /// Like iter, but only over the keys in `start..=end`
+    pub fn range_iter(&self, start: &[u8], end: &[u8]) -> Result<TreeIter> {
+        self.range_iter_bounds(Included(start), Included(end))
+    }
+
+    /// Like iter, but only over the keys between `start` and `end`, which may be
+    /// inclusive, exclusive or unbounded
+    pub fn range_iter_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<TreeIter> {
+        let start = start.map(<[u8]>::to_vec);
+        let end = end.map(<[u8]>::to_vec);
+        TreeIter::new(self.inner.memtables(), self.inner.runs(), start, end)
+    }
+
+    /// Like iter, but only over the keys starting with `prefix`. Runs whose key range or
+    /// prefix filter rule the prefix out are not read at all.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<TreeIter> {
+        let start = Included(prefix.to_vec());
+        let end = prefix_end(prefix);
+        let memtables = self.inner.memtables();
+        let runs = self
+            .inner
+            .runs()
+            .into_iter()
+            .filter(|run| run.may_contain_prefix(prefix))
+            .collect();
+        TreeIter::new(memtables, runs, start, end)
+    }
+
+    /// Like range_iter_bounds, in descending key order
+    pub fn range_iter_rev(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<RevTreeIter> {
+        self.range_iter_bounds(start, end)?.into_rev()
+    }
+
+    /// Returns the first `limit` keys between `start` and `end` and their values, in key
+    /// order, and where to continue if there are more.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::lsm::LSMTree;
+    /// use lsm_kv::options::Options;
+    /// use std::ops::Bound::{Excluded, Unbounded};
+    /// # let _ = std::fs::remove_dir_all("/tmp/doc_limit_test");
+    /// let lsm = LSMTree::open("/tmp/doc_limit_test", Options::default())?;
+    /// for key in ["a", "b", "c"].iter() {
+    ///     lsm.put(key, "value")?;
+    /// }
+    /// let page = lsm.range_with_limit(Unbounded, Unbounded, 2)?;
+    /// assert_eq!(2, page.entries.len());
+    /// let last = page.continuation.unwrap();
+    /// let page = lsm.range_with_limit(Excluded(&last[..]), Unbounded, 2)?;
+    /// assert_eq!(b"c".to_vec(), page.entries[0].0);
+    /// assert_eq!(None, page.continuation);
+    /// # Ok::<(), lsm_kv::error::Error>(())
+    /// ```
+    pub fn range_with_limit(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<RangePage> {
+        if limit == 0 {
+            return Err(Error::invalid_argument("limit must not be 0"));
         }
-        while !merge_ctx.done() {
-            entry = merge_ctx.next();
-            let res = self.vec_u8_to_str(&entry.value);
-            if res != TOMBSTONE.to_string() {
-                buffer_range.push(res);
-            }
+        RangePage::collect(self.range_iter_bounds(start, end)?, limit)
+    }
+
+    /// Like range_with_limit, in descending key order. The continuation is the end of
+    /// the next page.
+    pub fn range_with_limit_rev(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<RangePage> {
+        if limit == 0 {
+            return Err(Error::invalid_argument("limit must not be 0"));
         }
+        RangePage::collect(self.range_iter_rev(start, end)?, limit)
+    }
 
-        buffer_range
+    pub fn del(&self, key_str: &str) -> Result<()> {
+        self.del_bytes(key_str.as_bytes())
     }
 
-    pub fn del(&mut self, key_str: &str) {
-        self.put(key_str, TOMBSTONE);
+    pub fn del_bytes(&self, key: &[u8]) -> Result<()> {
+        self.del_with_options(key, &WriteOptions::default())
     }
 
-    pub fn load(&mut self) -> io::Result<()> {
-        //TODO iterate through every level subdir in the directory "/tmp/tree_name/" and load Runs
-        for depth in 0..self.levels.len() {
-            let level_dir_str = format!("/tmp/{}/{}/", self.tree_name, depth).to_string();
-            let level_dir: &Path = Path::new(&level_dir_str);
-            let max_size = self.levels[depth].max_run_size;
-            //visit every run and load into LSMTree vec<Level>
+    pub fn del_with_options(&self, key: &[u8], options: &WriteOptions) -> Result<()> {
+        check_write_size(ENTRY_HEADER_SIZE + key.len())?;
+        self.write_entries(vec![EntryT::tombstone(key.to_vec())], options)
+    }
+
+    //rebuild the levels, the full buffers and the buffer of a tree that was just created
+    //from its files. only the constructors call it, a second call would add them all again.
+    //state is the manifest create replayed, which says which run files belong to which
+    //level, and in what order.
+    fn load(&self, state: manifest::ManifestState) -> Result<()> {
+        let mut writer = self.writer();
+        let inner = &self.inner;
+        let depth = inner.levels.read().unwrap().len();
+        if state.levels.len() > depth {
+            return Err(Error::corruption("manifest has more levels than the tree"));
+        }
+        let mut loaded: Vec<Vec<Arc<run::Run>>> = Vec::new();
+        for (depth, run_metas) in state.levels.iter().enumerate() {
+            //run_metas is ordered from the newest run to the oldest, like Level::runs
+            let mut runs = Vec::new();
+            for meta in run_metas.iter() {
+                let mut cur_run = inner.new_run(depth, meta.file_number, meta.size as usize);
+                //println!("cur file path is {:?}", cur_run.tmp_file);
+                if fs::metadata(&cur_run.tmp_file)?.len() != meta.bytes {
+                    return Err(Error::corruption(format!(
+                        "run file {:?} has an unexpected size",
+                        cur_run.tmp_file
+                    )));
+                }
+                //the bloom filter, fence pointers and sizes are stored at the end of the run file
+                cur_run.load_metadata()?;
+                if cur_run.size != meta.size {
+                    return Err(Error::corruption(format!(
+                        "run file {:?} is corrupted",
+                        cur_run.tmp_file
+                    )));
+                }
+                runs.push(Arc::new(cur_run));
+            }
+            loaded.push(runs);
+            //println!("cur level has {} Runs", self.levels[depth].runs.len());
+        }
+        for (level, runs) in inner.levels.write().unwrap().iter_mut().zip(loaded) {
+            level.runs.extend(runs);
+        }
+
+        //logs of full buffers, which are only flushed if the manifest has their run
+        let mut sealed: Vec<u64> = Vec::new();
+        for file in fs::read_dir(&inner.path)? {
+            let name = file?.file_name();
+            if let Some(number) = name.to_str().and_then(wal::parse_sealed_log_name) {
+                sealed.push(number);
+            }
+        }
+        sealed.sort_unstable();
+        let next_file_number = match sealed.last() {
+            //the number of a full buffer may not have reached the manifest yet
+            Some(last) => max(state.next_file_number, last + 1),
+            None => state.next_file_number,
+        };
+        inner
+            .next_file_number
+            .store(next_file_number, Ordering::SeqCst);
+
+        //files in the level directories that the manifest does not know about were left by an
+        //interrupted flush or merge, or by a merge that finished before their deletion
+        let live: HashSet<PathBuf> = inner
+            .runs()
+            .iter()
+            .map(|run| run.tmp_file.clone())
+            .collect();
+        for depth in 0..depth {
+            let level_dir = inner.path.join(depth.to_string());
             if level_dir.is_dir() {
-                let files = fs::read_dir(level_dir)?;
-                let mut entries: Vec<PathBuf> = files
-                    .filter(Result::is_ok)
-                    .map(|e| e.unwrap().path())
-                    .collect();
-                //make sure we read from Run-0.text to Run-max_runs.txt
-                entries.sort();
-                for run_file in entries {
-                    let run_file_entry = run_file;
-                    //println!("cur file path is {:?}", run_file_entry);
-                    let mut cur_run = run::Run::from(
-                        max_size as u64,
-                        self.bf_bits_per_entry,
-                        depth,
-                        run_file_entry,
-                    );
-                    //reconstruct the bloom filter for cur_run. information like max_key, fence_pointers could be stored for easier reconstructions
-                    let mut counter = 0;
-                    for key in cur_run.get_keys().iter() {
-                        //println!("{} set", str::from_utf8(key).unwrap());
-                        if counter % (page_size::get() / ENTRY_SIZE) as u64 == 0 {
-                            cur_run.fence_pointers.push(key.clone());
-                        }
-                        cur_run.bloom_filter.set(key);
-                        counter += 1;
-                        cur_run.max_key = max(key.clone(), cur_run.max_key.clone());
+                for file in fs::read_dir(level_dir)? {
+                    let path = file?.path();
+                    if !live.contains(&path) {
+                        fs::remove_file(path)?;
                     }
-                    cur_run.size = counter;
-                    self.levels[depth].runs.push_back(cur_run);
                 }
             }
-            //println!("cur level has {} Runs", self.levels[depth].runs.len());
+        }
+        //start a compact manifest holding only the current runs
+        inner.manifest.lock().unwrap().rewrite(&state)?;
+
+        //rebuild the full buffers that had not been flushed yet, oldest first
+        let flushed: HashSet<u64> = inner.runs().iter().map(|run| run.file_number).collect();
+        let mut immutables = inner.immutables.write().unwrap();
+        for number in sealed {
+            let log = inner.path.join(wal::sealed_log_name(number));
+            if flushed.contains(&number) {
+                fs::remove_file(log)?;
+                continue;
+            }
+            let buffer: Arc<dyn MemTable> = inner.options.memtable.new_memtable().into();
+            for entry in wal::Wal::open(&log)?.replay()? {
+                buffer.put(entry, writer.sequence);
+                writer.sequence += 1;
+            }
+            immutables.push_front(Arc::new(Immutable { number, buffer }));
+        }
+        if !immutables.is_empty() {
+            self.schedule_background_work();
+        }
+
+        //rebuild the buffer from the writes that had not been flushed yet
+        let buffer = inner.buffer.read().unwrap();
+        for entry in writer.wal.replay()? {
+            buffer.put(entry, writer.sequence);
+            writer.sequence += 1;
         }
 
         Ok(())
     }
 
-    pub fn clear(&mut self) {
+    pub fn clear(&self) -> Result<()> {
+        let mut writer = self.writer();
+        //the files may belong to another tree by now
+        if writer.lock.is_none() {
+            return Err(Error::Closed);
+        }
+        //no background job may run while the files go away
+        self.worker_pool.join();
+        let inner = &self.inner;
         //remove all files and clear all Runs in self.levels
-        if let Ok(dir) = read_dir(format!("/tmp/{}/", self.tree_name)) {
-            for entry in dir {
-                if let Ok(entry) = entry {
-                    let path = entry.path();
-                    if entry.path().is_dir() {
-                        fs::remove_dir_all(path);
-                    } else {
-                        fs::remove_file(path);
-                    }
-                }
+        for entry in read_dir(&inner.path)? {
+            let path = entry?.path();
+            //the tree keeps its settings and its lock
+            if path.file_name() == Some(OPTIONS_FILE_NAME.as_ref())
+                || path.file_name() == Some(lock::LOCK_FILE_NAME.as_ref())
+            {
+                continue;
+            }
+            if path.is_dir() {
+                fs::remove_dir_all(path)?;
+            } else {
+                fs::remove_file(path)?;
             }
-            self.levels.clear();
-            self.buffer.empty();
         }
+        //the levels stay, so the tree can be written again
+        for (depth, level) in inner.levels.write().unwrap().iter_mut().enumerate() {
+            level.runs.clear();
+            fs::create_dir_all(inner.path.join(depth.to_string()))?;
+        }
+        *inner.buffer.write().unwrap() = inner.options.memtable.new_memtable().into();
+        inner.immutables.write().unwrap().clear();
+        writer.wal = Arc::new(wal::Wal::open(&inner.path.join(wal::WAL_FILE_NAME))?);
+        *inner.manifest.lock().unwrap() =
+            manifest::Manifest::open(&inner.path.join(manifest::MANIFEST_FILE_NAME))?;
+        inner.next_file_number.store(0, Ordering::SeqCst);
+        *inner.background_error.lock().unwrap() = None;
+        Ok(())
     }
 
-    pub fn close(&mut self) {
+    /// Flushes the buffer, waits for the background jobs and releases the data directory
+    /// for other trees. Writes, `clear` and `close` fail with `Error::Closed` afterwards.
+    pub fn close(&self) -> Result<()> {
+        let mut writer = self.writer();
+        if writer.lock.is_none() {
+            return Err(Error::Closed);
+        }
         //save the buffer as a Run in level 0 even if it is not full.
-        self.merge_down(0);
+        if !self.inner.buffer.read().unwrap().is_empty() {
+            self.seal_buffer(&mut writer)?;
+        }
+        //flush on this thread, after the background job running now if there is one
+        self.inner.background_work()?;
+        writer.lock = None;
+        Ok(())
+    }
+}
 
-        /*
-         * Flush the buffer to level 0.
-         */
-        let size = self.levels[0].max_run_size as u64;
-        let id = self.levels[0].runs.len();
-        self.levels[0].runs.push_front(run::Run::new(
-            size,
-            self.bf_bits_per_entry,
-            &self.tree_name,
-            0,
-            id,
-        ));
-        self.levels[0].runs[0].map_write();
+impl Drop for LSMTree {
+    //the background jobs use the files of the tree, so they finish before its lock goes
+    fn drop(&mut self) {
+        self.worker_pool.join();
+    }
+}
 
-        for entry_in_buf in self.buffer.entries.iter() {
-            self.levels[0].runs[0].put(&entry_in_buf);
-        }
-        self.levels[0].runs[0].unmap();
+//the log stores the length of a record, which holds every entry of a write, as u32. that
+//also keeps the lengths of keys and values, stored as u32 in entries, from overflowing.
+fn check_write_size(encoded_len: usize) -> Result<()> {
+    if encoded_len > u32::MAX as usize {
+        return Err(Error::invalid_argument(format!(
+            "a write of {} bytes is above the limit of {} bytes",
+            encoded_len,
+            u32::MAX
+        )));
+    }
+    Ok(())
+}
 
-        //buffer already written to levels.front().runs.front(). We can clear it now for inserting new entry.
-        self.buffer.empty();
+//the first key after all keys starting with prefix, if there is one
+fn prefix_end(prefix: &[u8]) -> Bound<KeyT> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Excluded(end);
+        }
     }
+    Unbounded
+}
+
+#[cfg(test)]
+fn test_options(write_buffer_size: u64, depth: u64, fanout: u64) -> Options {
+    Options::builder()
+        .write_buffer_size(write_buffer_size)
+        .depth(depth)
+        .fanout(fanout)
+        .bf_bits_per_entry(0.5)
+        .num_threads(4)
+        .build()
+        .unwrap()
 }
 
 #[test]
 fn test_close_load() {
     let test_size = 1000;
-    let mut lsm = LSMTree::new(8, 5, 8, 0.5, 4, "close_load_test".to_string());
+    let _ = fs::remove_dir_all("/tmp/close_load_test");
+    let lsm = LSMTree::new("close_load_test", test_options(1024, 5, 8)).unwrap();
     for i in 0..test_size {
-        lsm.put(&i.to_string(), &i.to_string());
+        lsm.put(&i.to_string(), &i.to_string()).unwrap();
     }
     for j in 0..test_size {
-        assert_eq!(Some(j.to_string()), lsm.get(&j.to_string()));
+        assert_eq!(Some(j.to_string()), lsm.get(&j.to_string()).unwrap());
     }
-    lsm.close();
-    println!("close done");
-    let mut lsm2 = LSMTree::new(8, 5, 8, 0.5, 4, "close_load_test".to_string());
-    lsm2.load();
-    println!("load done");
+    lsm.close().unwrap();
+    let num_runs = lsm.num_runs();
+    drop(lsm);
+    let lsm2 = LSMTree::new("close_load_test", test_options(1024, 5, 8)).unwrap();
+    assert_eq!(num_runs, lsm2.num_runs());
     for j in 0..test_size {
-        assert_eq!(Some(j.to_string()), lsm2.get(&j.to_string()));
+        assert_eq!(Some(j.to_string()), lsm2.get(&j.to_string()).unwrap());
+    }
+}
+
+#[test]
+fn test_variable_length() {
+    let test_size = 1000;
+    let _ = fs::remove_dir_all("/tmp/variable_length_test");
+    let key = |i: usize| format!("{:08x}-6f1d-4c2b-9a7e-{:012x}", i * 7919, i);
+    let value = |i: usize| format!("{{\"id\": {}, \"body\": \"{}\"}}", i, "x".repeat(i % 200));
+    let lsm = LSMTree::new("variable_length_test", test_options(2048, 5, 4)).unwrap();
+    for i in 0..test_size {
+        lsm.put(&key(i), &value(i)).unwrap();
+    }
+    for i in 0..test_size {
+        assert_eq!(Some(value(i)), lsm.get(&key(i)).unwrap());
+    }
+    assert_eq!(None, lsm.get("short").unwrap());
+    lsm.close().unwrap();
+    let lsm2 = LSMTree::new("variable_length_test", test_options(2048, 5, 4)).unwrap();
+    for i in 0..test_size {
+        assert_eq!(Some(value(i)), lsm2.get(&key(i)).unwrap());
+    }
+}
+
+#[test]
+fn test_bytes() {
+    let test_size: u32 = 500;
+    let _ = fs::remove_dir_all("/tmp/bytes_test");
+    //keys and values with padding, zero bytes and bytes that are not valid UTF-8
+    let key = |i: u32| {
+        let mut k = vec![0xff, 0];
+        k.extend_from_slice(&i.to_be_bytes());
+        k.push(b' ');
+        k
+    };
+    let value = |i: u32| {
+        let mut v = vec![b' ', 0xc3];
+        v.extend_from_slice(&i.to_le_bytes());
+        v.extend_from_slice(b"  ");
+        v
+    };
+    let lsm = LSMTree::new("bytes_test", test_options(2048, 5, 4)).unwrap();
+    for i in 0..test_size {
+        lsm.put_bytes(&key(i), &value(i)).unwrap();
+    }
+    lsm.del_bytes(&key(7)).unwrap();
+    for i in 0..test_size {
+        let expected = if i == 7 { None } else { Some(value(i)) };
+        assert_eq!(expected, lsm.get_bytes(&key(i)).unwrap());
+    }
+    assert_eq!(
+        vec![value(test_size - 2), value(test_size - 1)],
+        lsm.range_bytes(&key(test_size - 2), &key(test_size + 5))
+            .unwrap()
+    );
+    lsm.close().unwrap();
+    let lsm2 = LSMTree::new("bytes_test", test_options(2048, 5, 4)).unwrap();
+    for i in 0..test_size {
+        let expected = if i == 7 { None } else { Some(value(i)) };
+        assert_eq!(expected, lsm2.get_bytes(&key(i)).unwrap());
+    }
+    lsm2.put(" padded ", "  spaces  ").unwrap();
+    assert_eq!(
+        Some("  spaces  ".to_string()),
+        lsm2.get(" padded ").unwrap()
+    );
+}
+
+#[test]
+fn test_tombstone_value() {
+    let _ = fs::remove_dir_all("/tmp/tombstone_value_test");
+    let lsm = LSMTree::new("tombstone_value_test", test_options(512, 5, 2)).unwrap();
+    //"TOMBSTONE" is an ordinary value, only del hides a key
+    lsm.put("a", "TOMBSTONE").unwrap();
+    lsm.put("b", "value").unwrap();
+    lsm.del("b").unwrap();
+    for i in 0..100 {
+        lsm.put(&format!("filler{}", i), "x").unwrap();
+    }
+    assert_eq!(Some("TOMBSTONE".to_string()), lsm.get("a").unwrap());
+    assert_eq!(None, lsm.get("b").unwrap());
+    lsm.close().unwrap();
+    let lsm2 = LSMTree::new("tombstone_value_test", test_options(512, 5, 2)).unwrap();
+    assert_eq!(Some("TOMBSTONE".to_string()), lsm2.get("a").unwrap());
+    assert_eq!(None, lsm2.get("b").unwrap());
+}
+
+#[test]
+fn test_wal_recovery() {
+    let test_size = 100;
+    let _ = fs::remove_dir_all("/tmp/wal_recovery_test");
+    let lsm = LSMTree::new("wal_recovery_test", test_options(2048, 5, 4)).unwrap();
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string()).unwrap();
+    }
+    lsm.del("42").unwrap();
+    lsm.put("99", "updated").unwrap();
+    //simulate a crash: the tree is dropped without close, so the buffer is never flushed
+    drop(lsm);
+
+    let lsm2 = LSMTree::new("wal_recovery_test", test_options(2048, 5, 4)).unwrap();
+    for i in 0..test_size - 1 {
+        let expected = if i == 42 { None } else { Some(i.to_string()) };
+        assert_eq!(expected, lsm2.get(&i.to_string()).unwrap());
+    }
+    assert_eq!(Some("updated".to_string()), lsm2.get("99").unwrap());
+    //a clean close leaves nothing to replay
+    lsm2.close().unwrap();
+    assert_eq!(
+        0,
+        fs::metadata("/tmp/wal_recovery_test/wal.log")
+            .unwrap()
+            .len()
+    );
+}
+
+#[test]
+fn test_sync_policy() {
+    let _ = fs::remove_dir_all("/tmp/sync_policy_test");
+    let lsm = LSMTree::new("sync_policy_test", test_options(10_000, 5, 4)).unwrap();
+    lsm.put("fast", "1").unwrap();
+    assert_eq!(0, lsm.writer().wal.sync_count());
+    let billing = WriteOptions {
+        sync_policy: Some(wal::SyncPolicy::EveryWrite),
+    };
+    lsm.put_with_options(b"billing", b"2", &billing).unwrap();
+    assert_eq!(1, lsm.writer().wal.sync_count());
+    lsm.set_sync_policy(wal::SyncPolicy::EveryWrite);
+    lsm.del("fast").unwrap();
+    assert_eq!(2, lsm.writer().wal.sync_count());
+    let relaxed = WriteOptions {
+        sync_policy: Some(wal::SyncPolicy::NoSync),
+    };
+    lsm.put_with_options(b"fast", b"3", &relaxed).unwrap();
+    assert_eq!(2, lsm.writer().wal.sync_count());
+    assert_eq!(Some("3".to_string()), lsm.get("fast").unwrap());
+    assert_eq!(Some("2".to_string()), lsm.get("billing").unwrap());
+}
+
+#[test]
+fn test_interval_sync() {
+    let _ = fs::remove_dir_all("/tmp/interval_sync_test");
+    let options = Options {
+        sync_policy: wal::SyncPolicy::Interval(Duration::from_millis(200)),
+        ..test_options(10_000, 3, 4)
+    };
+    let lsm = LSMTree::new("interval_sync_test", options).unwrap();
+    lsm.put("a", "1").unwrap();
+    lsm.put("b", "2").unwrap();
+    assert_eq!(0, lsm.writer().wal.sync_count());
+    //the tree goes idle, the log is still synced once the interval is over
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(1, lsm.writer().wal.sync_count());
+}
+
+#[test]
+fn test_group_commit() {
+    let _ = fs::remove_dir_all("/tmp/group_commit_test");
+    //a buffer large enough that the log is never sealed
+    let options = Options {
+        sync_policy: wal::SyncPolicy::EveryWrite,
+        ..test_options(1 << 24, 3, 4)
+    };
+    let lsm = Arc::new(LSMTree::new("group_commit_test", options).unwrap());
+    let num_threads = 8;
+    let writes_per_thread = 50;
+    let handles: Vec<_> = (0..num_threads)
+        .map(|t| {
+            let lsm = lsm.clone();
+            thread::spawn(move || {
+                for i in 0..writes_per_thread {
+                    lsm.put(&format!("{}-{}", t, i), "value").unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    //writers that append while another one runs fsync share the next one
+    assert!(lsm.writer().wal.sync_count() < num_threads * writes_per_thread);
+    assert_eq!(0, lsm.inner.immutables.read().unwrap().len());
+    assert_eq!(Some("value".to_string()), lsm.get("7-49").unwrap());
+}
+
+#[test]
+fn test_manifest_order() {
+    let _ = fs::remove_dir_all("/tmp/manifest_order_test");
+    //level 0 holds up to 16 runs, so run file numbers go past 10
+    let lsm = LSMTree::new("manifest_order_test", test_options(512, 3, 16)).unwrap();
+    for round in 0..15 {
+        for i in 0..4 {
+            lsm.put(&format!("key{}", i), &format!("round{}", round))
+                .unwrap();
+        }
+    }
+    lsm.close().unwrap();
+    //left overs from some interrupted merge and some interrupted flush
+    fs::write("/tmp/manifest_order_test/0/run_file-999.txt", b"junk").unwrap();
+    fs::write("/tmp/manifest_order_test/0/run_file-998.tmp", b"junk").unwrap();
+
+    let lsm2 = LSMTree::new("manifest_order_test", test_options(512, 3, 16)).unwrap();
+    for i in 0..4 {
+        assert_eq!(
+            Some("round14".to_string()),
+            lsm2.get(&format!("key{}", i)).unwrap()
+        );
+    }
+    assert!(!Path::new("/tmp/manifest_order_test/0/run_file-999.txt").exists());
+    assert!(!Path::new("/tmp/manifest_order_test/0/run_file-998.tmp").exists());
+    //new runs do not overwrite the loaded ones
+    for i in 0..8 {
+        lsm2.put(&format!("new{}", i), "new").unwrap();
+    }
+    lsm2.close().unwrap();
+    let lsm3 = LSMTree::new("manifest_order_test", test_options(512, 3, 16)).unwrap();
+    for i in 0..4 {
+        assert_eq!(
+            Some("round14".to_string()),
+            lsm3.get(&format!("key{}", i)).unwrap()
+        );
+    }
+    for i in 0..8 {
+        assert_eq!(
+            Some("new".to_string()),
+            lsm3.get(&format!("new{}", i)).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_open_path() {
+    let _ = fs::remove_dir_all("/tmp/open_path_test");
+    let path = Path::new("/tmp/open_path_test/volume/tree");
+    let options = Options {
+        write_buffer_size: 1024,
+        ..Options::default()
+    };
+    let lsm = LSMTree::open(path, options).unwrap();
+    assert_eq!(path, lsm.path());
+    for i in 0..100 {
+        lsm.put(&i.to_string(), &i.to_string()).unwrap();
+    }
+    lsm.close().unwrap();
+    assert!(path.join(manifest::MANIFEST_FILE_NAME).is_file());
+    assert!(path.join(wal::WAL_FILE_NAME).is_file());
+    assert!(fs::read_dir(path.join("0")).unwrap().count() > 0);
+    assert!(!Path::new("/tmp/tree").exists());
+
+    let lsm2 = LSMTree::open(path, options).unwrap();
+    for i in 0..100 {
+        assert_eq!(Some(i.to_string()), lsm2.get(&i.to_string()).unwrap());
+    }
+
+    //a regular file where the directory should be
+    fs::write("/tmp/open_path_test/file", b"").unwrap();
+    assert!(LSMTree::open("/tmp/open_path_test/file", options).is_err());
+    assert!(LSMTree::open("/tmp/open_path_test/file/tree", options).is_err());
+}
+
+#[test]
+fn test_persisted_options() {
+    let _ = fs::remove_dir_all("/tmp/persisted_options_test");
+    let lsm = LSMTree::open("/tmp/persisted_options_test", test_options(1024, 4, 3)).unwrap();
+    for i in 0..200 {
+        lsm.put(&i.to_string(), &i.to_string()).unwrap();
+    }
+    lsm.close().unwrap();
+
+    //the shape of the tree comes from the OPTIONS file, not from the caller
+    let options = Options::builder().num_threads(2).build().unwrap();
+    let lsm2 = LSMTree::open("/tmp/persisted_options_test", options).unwrap();
+    assert_eq!(1024, lsm2.options().write_buffer_size);
+    assert_eq!(4, lsm2.options().depth);
+    assert_eq!(3, lsm2.options().fanout);
+    assert_eq!(2, lsm2.options().num_threads);
+    for i in 0..200 {
+        assert_eq!(Some(i.to_string()), lsm2.get(&i.to_string()).unwrap());
+    }
+
+    let invalid = Options {
+        fanout: 1,
+        ..Options::default()
+    };
+    assert!(LSMTree::open("/tmp/persisted_options_test/new", invalid).is_err());
+}
+
+#[test]
+fn test_errors() {
+    let _ = fs::remove_dir_all("/tmp/errors_test");
+    //a single level of two runs holds 8 entries
+    let lsm = LSMTree::new("errors_test", test_options(512, 1, 2)).unwrap();
+    //the third full buffer has nowhere to go, which only its flush in the background finds.
+    //the write that handed it to the flush went through.
+    let mut written = 0;
+    let background = loop {
+        lsm.put(&written.to_string(), &written.to_string()).unwrap();
+        written += 1;
+        if let Err(e) = lsm.wait_for_background_work() {
+            break e;
+        }
+    };
+    assert!(matches!(background, Error::NoSpace));
+    assert_eq!(2, lsm.num_runs());
+    let next = written.to_string();
+    assert!(matches!(lsm.put(&next, &next), Err(Error::NoSpace)));
+    assert_eq!(None, lsm.get(&next).unwrap());
+    //the full buffer that could not be flushed is still read
+    for i in 0..written {
+        assert_eq!(Some(i.to_string()), lsm.get(&i.to_string()).unwrap());
+    }
+    assert!(matches!(lsm.close(), Err(Error::NoSpace)));
+
+    //an open run keeps its file mapped, so it is only missed when the tree is opened
+    //again. that is an error, not a crash.
+    let run_file = lsm.get_run(1).unwrap().tmp_file.clone();
+    fs::remove_file(run_file).unwrap();
+    assert_eq!(Some("0".to_string()), lsm.get("0").unwrap());
+    drop(lsm);
+    assert!(matches!(
+        LSMTree::open("/tmp/errors_test", test_options(512, 1, 2)),
+        Err(Error::Io(_))
+    ));
+}
+
+#[test]
+fn test_lock() {
+    let _ = fs::remove_dir_all("/tmp/lock_test");
+    let lsm = LSMTree::open("/tmp/lock_test", test_options(1024, 5, 8)).unwrap();
+    lsm.put("key", "value").unwrap();
+    assert!(matches!(
+        LSMTree::open("/tmp/lock_test", test_options(1024, 5, 8)),
+        Err(Error::AlreadyInUse(_))
+    ));
+    lsm.close().unwrap();
+    assert!(matches!(lsm.put("key", "other"), Err(Error::Closed)));
+
+    let lsm2 = LSMTree::open("/tmp/lock_test", test_options(1024, 5, 8)).unwrap();
+    //dropping the tree releases the lock as well
+    drop(lsm2);
+    let lsm3 = LSMTree::open("/tmp/lock_test", test_options(1024, 5, 8)).unwrap();
+    assert_eq!(Some("value".to_string()), lsm3.get("key").unwrap());
+}
+
+#[test]
+fn test_write_size() {
+    let _ = fs::remove_dir_all("/tmp/write_size_test");
+    let lsm = LSMTree::new("write_size_test", test_options(10_000, 3, 4)).unwrap();
+    //lengths that do not fit the u32 they are stored as. the pages are only allocated, the
+    //write is rejected before they are copied.
+    let huge = vec![0u8; u32::MAX as usize];
+    assert!(matches!(
+        lsm.put_bytes(b"key", &huge),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        lsm.del_bytes(&huge),
+        Err(Error::InvalidArgument(_))
+    ));
+    drop(huge);
+    //entries that fit on their own but not in one record
+    let mut batch = WriteBatch::new();
+    for key in [b"a", b"b"].iter() {
+        batch
+            .entries
+            .push(EntryT::new(key.to_vec(), vec![0u8; 1 << 31]));
+    }
+    assert!(matches!(lsm.write(batch), Err(Error::InvalidArgument(_))));
+    let mut batch = WriteBatch::new();
+    batch.put(b"key", b"value");
+    lsm.write(batch).unwrap();
+    assert_eq!(Some("value".to_string()), lsm.get("key").unwrap());
+}
+
+#[test]
+fn test_closed() {
+    let _ = fs::remove_dir_all("/tmp/closed_test");
+    let lsm = LSMTree::open("/tmp/closed_test", test_options(1024, 5, 8)).unwrap();
+    lsm.put("key", "value").unwrap();
+    lsm.close().unwrap();
+    let other = LSMTree::open("/tmp/closed_test", test_options(1024, 5, 8)).unwrap();
+    //the closed tree leaves the files of the tree holding the directory now alone
+    assert!(matches!(lsm.clear(), Err(Error::Closed)));
+    assert!(matches!(lsm.close(), Err(Error::Closed)));
+    assert_eq!(Some("value".to_string()), other.get("key").unwrap());
+    other.put("key2", "value2").unwrap();
+    drop(other);
+    let reopened = LSMTree::open("/tmp/closed_test", test_options(1024, 5, 8)).unwrap();
+    assert_eq!(Some("value".to_string()), reopened.get("key").unwrap());
+    assert_eq!(Some("value2".to_string()), reopened.get("key2").unwrap());
+}
+
+#[test]
+fn test_iter() {
+    use std::collections::BTreeMap;
+    let _ = fs::remove_dir_all("/tmp/iter_test");
+    let lsm = LSMTree::open("/tmp/iter_test", test_options(1024, 4, 3)).unwrap();
+    let mut expected = BTreeMap::new();
+    let mut rng = thread_rng();
+    //enough writes to fill runs on several levels
+    for i in 0..600 {
+        let key = format!("{:04}", rng.gen_range(0, 300)).into_bytes();
+        if i % 5 == 0 {
+            lsm.del_bytes(&key).unwrap();
+            expected.remove(&key);
+        } else {
+            //large enough values that runs deeper down span several pages
+            let value = format!("v{}-{}", i, "x".repeat(100)).into_bytes();
+            lsm.put_bytes(&key, &value).unwrap();
+            expected.insert(key, value);
+        }
+    }
+    lsm.wait_for_background_work().unwrap();
+    assert!(lsm.inner.runs().iter().any(|run| run.num_pages() > 1));
+
+    for k in 0..300 {
+        let key = format!("{:04}", k).into_bytes();
+        assert_eq!(expected.get(&key).cloned(), lsm.get_bytes(&key).unwrap());
+    }
+    let all: Vec<(KeyT, ValueT)> = expected.clone().into_iter().collect();
+    let mut iter = lsm.iter().unwrap();
+    let forward: Vec<(KeyT, ValueT)> = iter.by_ref().map(|item| item.unwrap()).collect();
+    assert_eq!(all, forward);
+    let mut backward = Vec::new();
+    while let Some(item) = iter.prev() {
+        backward.push(item.unwrap());
+    }
+    backward.reverse();
+    assert_eq!(all, backward);
+
+    let (start, end) = (b"0100".to_vec(), b"0199".to_vec());
+    let in_range: Vec<(KeyT, ValueT)> = expected
+        .range(start.clone()..=end.clone())
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let mut iter = lsm.range_iter(&start, &end).unwrap();
+    assert_eq!(
+        in_range,
+        iter.by_ref()
+            .map(|item| item.unwrap())
+            .collect::<Vec<(KeyT, ValueT)>>()
+    );
+    iter.seek(b"0150").unwrap();
+    let after: Vec<&(KeyT, ValueT)> = in_range
+        .iter()
+        .filter(|(k, _)| k.as_slice() >= &b"0150"[..])
+        .collect();
+    if let Some(first) = after.first() {
+        assert_eq!(**first, iter.next().unwrap().unwrap());
+        //prev after next returns the same key
+        assert_eq!(**first, iter.prev().unwrap().unwrap());
+    }
+    iter.seek(b"0000").unwrap();
+    assert!(iter.prev().is_none());
+    assert_eq!(
+        in_range.first(),
+        iter.next().map(|item| item.unwrap()).as_ref()
+    );
+}
+
+#[test]
+fn test_iter_snapshot() {
+    for kind in [MemTableKind::BTree, MemTableKind::SkipList].iter() {
+        let path = "/tmp/iter_snapshot_test";
+        let _ = fs::remove_dir_all(path);
+        let options = Options::builder().memtable(*kind).build().unwrap();
+        let lsm = LSMTree::open(path, options).unwrap();
+        let mut expected = Vec::new();
+        for i in 0..100 {
+            let key = format!("{:03}", i).into_bytes();
+            lsm.put_bytes(&key, b"old").unwrap();
+            if i % 3 == 0 {
+                lsm.del_bytes(&key).unwrap();
+            } else {
+                expected.push((key, b"old".to_vec()));
+            }
+        }
+        let mut iter = lsm.iter().unwrap();
+        let mut rev = lsm.range_iter_rev(Unbounded, Unbounded).unwrap();
+        let mut seen = vec![iter.next().unwrap().unwrap()];
+        let mut seen_rev = vec![rev.next().unwrap().unwrap()];
+        //overwrite, delete and add keys on both sides of the iterators
+        for i in 0..100 {
+            let key = format!("{:03}", i).into_bytes();
+            if i % 2 == 0 {
+                lsm.del_bytes(&key).unwrap();
+            } else {
+                lsm.put_bytes(&key, b"new").unwrap();
+            }
+            lsm.put_bytes(format!("{:03}a", i).as_bytes(), b"new")
+                .unwrap();
+        }
+        seen.extend(iter.map(|item| item.unwrap()));
+        assert_eq!(expected, seen);
+        seen_rev.extend(rev.map(|item| item.unwrap()));
+        seen_rev.reverse();
+        assert_eq!(expected, seen_rev);
+        //a new page sees the writes
+        let page = lsm.range_with_limit(Unbounded, Unbounded, 2).unwrap();
+        assert_eq!(
+            vec![
+                (b"000a".to_vec(), b"new".to_vec()),
+                (b"001".to_vec(), b"new".to_vec())
+            ],
+            page.entries
+        );
+        lsm.close().unwrap();
     }
 }
 
 #[test]
 fn test_range() {
-    let mut lsm = LSMTree::new(100, 5, 10, 0.5, 4, "hello".to_string());
-    lsm.put("hello", "world");
-    lsm.put("facebook", "google");
-    lsm.put("amazon", "linkedin");
-    assert_eq!(vec!["linkedin", "google"], lsm.range("amazon", "facebook"));
+    let lsm = LSMTree::new("hello", test_options(10_000, 5, 10)).unwrap();
+    lsm.put("hello", "world").unwrap();
+    lsm.put("facebook", "google").unwrap();
+    lsm.put("amazon", "linkedin").unwrap();
+    assert_eq!(
+        vec!["linkedin", "google"],
+        lsm.range("amazon", "facebook").unwrap()
+    );
+}
+
+#[test]
+fn test_range_model() {
+    use std::collections::BTreeMap;
+    let _ = fs::remove_dir_all("/tmp/range_model_test");
+    //small runs and a small fanout, so levels hold several runs and data reaches the last level
+    let lsm = LSMTree::open("/tmp/range_model_test", test_options(2048, 5, 3)).unwrap();
+    let mut model: BTreeMap<KeyT, ValueT> = BTreeMap::new();
+    let mut rng = thread_rng();
+    let key = |k: u32| format!("{:05}", k).into_bytes();
+    for i in 0..1500 {
+        let k = key(rng.gen_range(0, 500));
+        if rng.gen_range(0, 4) == 0 {
+            lsm.del_bytes(&k).unwrap();
+            model.remove(&k);
+        } else {
+            let value = format!("{}-{}", i, "v".repeat(rng.gen_range(0, 80))).into_bytes();
+            lsm.put_bytes(&k, &value).unwrap();
+            model.insert(k, value);
+        }
+        if i % 100 == 99 {
+            for _ in 0..10 {
+                let (a, b) = (rng.gen_range(0, 520), rng.gen_range(0, 520));
+                let (start, end) = (key(a.min(b)), key(a.max(b)));
+                let expected: Vec<ValueT> = model
+                    .range(start.clone()..=end.clone())
+                    .map(|(_, v)| v.clone())
+                    .collect();
+                assert_eq!(expected, lsm.range_bytes(&start, &end).unwrap());
+            }
+        }
+    }
+    lsm.wait_for_background_work().unwrap();
+    assert!(
+        lsm.inner
+            .levels
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|level| level.runs.len() > 1)
+            .count()
+            > 1
+    );
+    let all: Vec<ValueT> = model.values().cloned().collect();
+    assert_eq!(all, lsm.range_bytes(b"", b"99999").unwrap());
+    assert!(lsm.range_bytes(b"00300", b"00200").unwrap().is_empty());
+}
+
+#[test]
+fn test_range_with_limit() {
+    use std::collections::BTreeMap;
+    use std::ops::Bound::Excluded;
+    let _ = fs::remove_dir_all("/tmp/range_limit_test");
+    let lsm = LSMTree::open("/tmp/range_limit_test", test_options(1024, 5, 3)).unwrap();
+    let mut model: BTreeMap<KeyT, ValueT> = BTreeMap::new();
+    let key = |k: u32| format!("{:04}", k).into_bytes();
+    for i in 0..400 {
+        let k = key((i * 37) % 250);
+        if i % 7 == 0 {
+            lsm.del_bytes(&k).unwrap();
+            model.remove(&k);
+        } else {
+            lsm.put_bytes(&k, &key(i)).unwrap();
+            model.insert(k, key(i));
+        }
+    }
+    let all: Vec<(KeyT, ValueT)> = model.clone().into_iter().collect();
+
+    //page through everything, both ways
+    let mut forward = Vec::new();
+    let mut cont: Option<KeyT> = None;
+    loop {
+        let start = match &cont {
+            Some(k) => Excluded(&k[..]),
+            None => Unbounded,
+        };
+        let page = lsm.range_with_limit(start, Unbounded, 7).unwrap();
+        assert!(page.entries.len() <= 7);
+        forward.extend(page.entries);
+        cont = page.continuation;
+        if cont.is_none() {
+            break;
+        }
+    }
+    assert_eq!(all, forward);
+
+    let mut backward = Vec::new();
+    let mut cont: Option<KeyT> = None;
+    loop {
+        let end = match &cont {
+            Some(k) => Excluded(&k[..]),
+            None => Unbounded,
+        };
+        let page = lsm.range_with_limit_rev(Unbounded, end, 10).unwrap();
+        backward.extend(page.entries);
+        cont = page.continuation;
+        if cont.is_none() {
+            break;
+        }
+    }
+    backward.reverse();
+    assert_eq!(all, backward);
+
+    //exclusive ends
+    let (start, end) = (key(20), key(120));
+    let expected: Vec<(KeyT, ValueT)> = model
+        .range((Excluded(start.clone()), Excluded(end.clone())))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let got: Vec<(KeyT, ValueT)> = lsm
+        .range_iter_bounds(Excluded(&start[..]), Excluded(&end[..]))
+        .unwrap()
+        .map(|item| item.unwrap())
+        .collect();
+    assert_eq!(expected, got);
+    let page = lsm
+        .range_with_limit_rev(Excluded(&start[..]), Excluded(&end[..]), 3)
+        .unwrap();
+    let newest_three: Vec<(KeyT, ValueT)> = expected.iter().rev().take(3).cloned().collect();
+    assert_eq!(newest_three, page.entries);
+    assert_eq!(Some(newest_three[2].0.clone()), page.continuation);
+    //exactly limit keys left means no continuation
+    let page = lsm
+        .range_with_limit(Excluded(&start[..]), Excluded(&end[..]), expected.len())
+        .unwrap();
+    assert_eq!(None, page.continuation);
+    assert!(matches!(
+        lsm.range_with_limit(Unbounded, Unbounded, 0),
+        Err(Error::InvalidArgument(_))
+    ));
+}
+
+#[test]
+fn test_scan_prefix() {
+    let _ = fs::remove_dir_all("/tmp/scan_prefix_test");
+    let options = Options {
+        prefix_len: 8,
+        ..test_options(2048, 5, 3)
+    };
+    let lsm = LSMTree::open("/tmp/scan_prefix_test", options).unwrap();
+    for i in 0..300u32 {
+        let key = format!("user/{:03}/{:03}", i % 30, i);
+        lsm.put(&key, &i.to_string()).unwrap();
+    }
+    lsm.del("user/007/097").unwrap();
+    lsm.put("user/007/127", "new").unwrap();
+    lsm.put("user/0070", "longer user").unwrap();
+    lsm.put("user", "no slash").unwrap();
+
+    let scan = |lsm: &LSMTree, prefix: &str| -> Vec<(String, String)> {
+        lsm.scan_prefix(prefix.as_bytes())
+            .unwrap()
+            .map(|item| {
+                let (k, v) = item.unwrap();
+                (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap())
+            })
+            .collect()
+    };
+    let user7 = scan(&lsm, "user/007/");
+    let expected: Vec<(String, String)> = (0..10)
+        .map(|j| 7 + 30 * j)
+        .filter(|i| *i != 97)
+        .map(|i| {
+            let value = if i == 127 {
+                "new".to_string()
+            } else {
+                i.to_string()
+            };
+            (format!("user/007/{:03}", i), value)
+        })
+        .collect();
+    assert_eq!(expected, user7);
+    assert_eq!(expected.len() + 1, scan(&lsm, "user/007").len());
+    assert_eq!(301, scan(&lsm, "user").len());
+    assert_eq!(301, scan(&lsm, "").len());
+    assert!(scan(&lsm, "user/099").is_empty());
+    assert!(scan(&lsm, "admin").is_empty());
+
+    //the prefix filter survives a reopen
+    drop(lsm);
+    let lsm = LSMTree::open("/tmp/scan_prefix_test", test_options(2048, 5, 3)).unwrap();
+    assert_eq!(8, lsm.options().prefix_len);
+    assert_eq!(expected, scan(&lsm, "user/007/"));
+}
+
+#[test]
+fn test_prefix_end() {
+    assert_eq!(Excluded(b"ab".to_vec()), prefix_end(b"aa"));
+    assert_eq!(Excluded(b"b".to_vec()), prefix_end(b"a\xff\xff"));
+    assert_eq!(Unbounded, prefix_end(b"\xff"));
+    assert_eq!(Unbounded, prefix_end(b""));
+}
+
+#[test]
+fn test_block_cache() {
+    let _ = fs::remove_dir_all("/tmp/block_cache_test");
+    let cache = Arc::new(BlockCache::new(1 << 20));
+    let a = LSMTree::open_with_cache(
+        "/tmp/block_cache_test/a",
+        test_options(8192, 4, 4),
+        cache.clone(),
+    )
+    .unwrap();
+    let b = LSMTree::open_with_cache(
+        "/tmp/block_cache_test/b",
+        test_options(8192, 4, 4),
+        cache.clone(),
+    )
+    .unwrap();
+    for i in 0..200 {
+        a.put(&i.to_string(), "a").unwrap();
+        b.put(&i.to_string(), "b").unwrap();
+    }
+    a.wait_for_background_work().unwrap();
+    b.wait_for_background_work().unwrap();
+    //bloom filter false positives may read a few more pages, so only compare counters
+    //before and after repeating the same lookups
+    assert_eq!(Some("a".to_string()), a.get("5").unwrap());
+    let misses = cache.misses();
+    assert!(misses > 0);
+    let hits = cache.hits();
+    assert_eq!(Some("a".to_string()), a.get("5").unwrap());
+    assert!(cache.hits() > hits);
+    assert_eq!(misses, cache.misses());
+    //a and b share the cache but not their pages
+    assert_eq!(Some("b".to_string()), b.get("5").unwrap());
+    assert!(cache.misses() > misses);
+    let misses = cache.misses();
+    let hits = cache.hits();
+    assert_eq!(Some("b".to_string()), b.get("5").unwrap());
+    assert!(cache.hits() > hits);
+    assert_eq!(misses, cache.misses());
+    assert_eq!(b.range("1", "2").unwrap(), vec!["b"; 112]);
+    assert!(cache.usage() > 0);
+
+    //runs written after a clear reuse file names, not cached pages
+    a.clear().unwrap();
+    for i in 0..200 {
+        a.put(&i.to_string(), "new").unwrap();
+    }
+    assert_eq!(Some("new".to_string()), a.get("5").unwrap());
+    assert_eq!(a.range("1", "2").unwrap(), vec!["new"; 112]);
+
+    //a tree without a cache
+    let c = LSMTree::open(
+        "/tmp/block_cache_test/c",
+        Options {
+            block_cache_size: 0,
+            ..test_options(8192, 4, 4)
+        },
+    )
+    .unwrap();
+    assert!(c.block_cache().is_none());
+    assert!(a.block_cache().is_some());
 }
 
 #[test]
 fn test_clear() {
     let test_size = 1000;
-    let mut lsm = LSMTree::new(8, 5, 8, 0.5, 4, "clear_test".to_string());
+    let lsm = LSMTree::new("clear_test", test_options(1024, 5, 8)).unwrap();
     for i in 0..test_size {
-        lsm.put(&i.to_string(), &i.to_string());
+        lsm.put(&i.to_string(), &i.to_string()).unwrap();
     }
-    lsm.clear();
+    lsm.clear().unwrap();
     for j in 0..test_size {
-        assert_eq!(None, lsm.get(&j.to_string()));
+        assert_eq!(None, lsm.get(&j.to_string()).unwrap());
+    }
+}
+
+#[test]
+fn test_background_flush() {
+    fn copy_dir(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for file in fs::read_dir(from).unwrap() {
+            let path = file.unwrap().path();
+            let target = to.join(path.file_name().unwrap());
+            if path.is_dir() {
+                copy_dir(&path, &target);
+            } else {
+                fs::copy(&path, &target).unwrap();
+            }
+        }
+    }
+    let sealed_logs = |path: &Path| -> usize {
+        fs::read_dir(path)
+            .unwrap()
+            .filter(|file| {
+                let name = file.as_ref().unwrap().file_name();
+                wal::parse_sealed_log_name(name.to_str().unwrap()).is_some()
+            })
+            .count()
+    };
+    let _ = fs::remove_dir_all("/tmp/background_flush_test");
+    let path = Path::new("/tmp/background_flush_test/tree");
+    let crashed = Path::new("/tmp/background_flush_test/crashed");
+    let key = |i: usize| format!("{:03}", i);
+    let lsm = LSMTree::open(path, test_options(1024, 5, 4)).unwrap();
+    let mut written = 0;
+    {
+        //no background job gets to flush meanwhile
+        let _running = lsm.inner.background.lock().unwrap();
+        while lsm.inner.immutables.read().unwrap().len() < 2 {
+            lsm.put(&key(written), &written.to_string()).unwrap();
+            written += 1;
+        }
+        assert_eq!(0, lsm.num_runs());
+        for i in 0..written {
+            assert_eq!(Some(i.to_string()), lsm.get(&key(i)).unwrap());
+        }
+        assert_eq!(written, lsm.iter().unwrap().count());
+        //a crash now leaves the logs of both full buffers behind
+        assert_eq!(2, sealed_logs(path));
+        copy_dir(path, crashed);
+    }
+    lsm.wait_for_background_work().unwrap();
+    assert!(lsm.inner.immutables.read().unwrap().is_empty());
+    assert_eq!(2, lsm.num_runs());
+    assert_eq!(0, sealed_logs(path));
+
+    let recovered = LSMTree::open(crashed, test_options(1024, 5, 4)).unwrap();
+    recovered.wait_for_background_work().unwrap();
+    assert_eq!(2, recovered.num_runs());
+    assert_eq!(0, sealed_logs(crashed));
+    for i in 0..written {
+        assert_eq!(Some(i.to_string()), recovered.get(&key(i)).unwrap());
+    }
+    //the log of a flushed buffer that a crash left behind is not replayed again
+    let run_number = recovered.get_run(0).unwrap().file_number;
+    drop(recovered);
+    let stale = wal::Wal::open(&crashed.join(wal::sealed_log_name(run_number))).unwrap();
+    stale
+        .append(
+            &EntryT::new(key(0).into_bytes(), b"stale".to_vec()),
+            wal::SyncPolicy::NoSync,
+        )
+        .unwrap();
+    let recovered = LSMTree::open(crashed, test_options(1024, 5, 4)).unwrap();
+    assert_eq!(Some("0".to_string()), recovered.get(&key(0)).unwrap());
+    assert_eq!(0, sealed_logs(crashed));
+}
+
+#[test]
+fn test_write_buffer_size() {
+    let _ = fs::remove_dir_all("/tmp/write_buffer_size_test");
+    let lsm = LSMTree::open("/tmp/write_buffer_size_test", test_options(4096, 5, 4)).unwrap();
+    {
+        let levels = lsm.inner.levels.read().unwrap();
+        assert_eq!(4096, levels[0].max_run_size);
+        assert_eq!(4 * 4096, levels[1].max_run_size);
+    }
+    //the buffer fills up by the bytes of its entries, not by their number
+    let _running = lsm.inner.background.lock().unwrap();
+    let large = vec![b'v'; 1000];
+    let mut written = 0;
+    while lsm.inner.immutables.read().unwrap().is_empty() {
+        lsm.put_bytes(format!("large{}", written).as_bytes(), &large)
+            .unwrap();
+        written += 1;
+    }
+    //the buffer was full after the fourth entry and sealed by the fifth write, while
+    //as many small entries fill much less of it
+    assert_eq!(5, written);
+    let immutable = lsm.inner.immutables.read().unwrap()[0].clone();
+    assert_eq!(4, immutable.buffer.len());
+    assert!(immutable.buffer.approximate_memory_usage() >= 4096);
+    for i in 0..20 {
+        lsm.put(&format!("small{}", i), "v").unwrap();
+    }
+    assert_eq!(1, lsm.inner.immutables.read().unwrap().len());
+}
+
+#[test]
+fn test_write_stall() {
+    use std::sync::atomic::AtomicBool;
+    let _ = fs::remove_dir_all("/tmp/write_stall_test");
+    let options = Options {
+        slowdown_writes_immutables: 1,
+        stop_writes_immutables: 2,
+        ..test_options(1024, 5, 4)
+    };
+    let lsm = Arc::new(LSMTree::open("/tmp/write_stall_test", options).unwrap());
+    let buffer_full = |lsm: &LSMTree| {
+        lsm.inner.buffer.read().unwrap().approximate_memory_usage() as u64
+            >= options.write_buffer_size
+    };
+    let mut keys = (0..).map(|i: u32| i.to_string());
+    while !buffer_full(&lsm) {
+        lsm.put(&keys.next().unwrap(), "v").unwrap();
+    }
+    assert_eq!(Statistics::default(), lsm.statistics());
+    let running = lsm.inner.background.lock().unwrap();
+    //the first full buffer slows down the writes after the one that sealed it
+    lsm.put(&keys.next().unwrap(), "v").unwrap();
+    let mut slowed = 0;
+    while !buffer_full(&lsm) {
+        lsm.put(&keys.next().unwrap(), "v").unwrap();
+        slowed += 1;
+    }
+    assert!(slowed > 0);
+    assert_eq!(slowed, lsm.statistics().slowdown_writes);
+    assert!(lsm.statistics().stall_time >= SLOWDOWN_DELAY * slowed as u32);
+    //the second one stops them until a flush is done
+    lsm.put(&keys.next().unwrap(), "v").unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let lsm = lsm.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            lsm.put("stopped", "v").unwrap();
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert!(!done.load(Ordering::SeqCst));
+    //the stopped writer waits without the writer lock
+    assert!(lsm.writer.try_lock().is_ok());
+    //reads go on meanwhile
+    assert_eq!(Some("v".to_string()), lsm.get("3").unwrap());
+    drop(running);
+    writer.join().unwrap();
+    let statistics = lsm.statistics();
+    assert_eq!(1, statistics.stopped_writes);
+    assert!(statistics.stall_time >= Duration::from_millis(50));
+    lsm.wait_for_background_work().unwrap();
+    assert_eq!(Some("v".to_string()), lsm.get("stopped").unwrap());
+}
+
+#[test]
+fn test_merge_backpressure() {
+    let _ = fs::remove_dir_all("/tmp/merge_backpressure_test");
+    //only the room of level 0 slows writes down here, not the number of full buffers
+    let options = Options {
+        slowdown_writes_immutables: 3,
+        stop_writes_immutables: 4,
+        ..test_options(1024, 3, 2)
+    };
+    let lsm = LSMTree::open("/tmp/merge_backpressure_test", options).unwrap();
+    let remaining =
+        |lsm: &LSMTree, level: usize| lsm.inner.levels.read().unwrap()[level].remaining();
+    let mut keys = (0..).map(|i: u32| format!("{:06}", i));
+    //fill level 0 and level 1, so the next flush has to merge both of them down first
+    while remaining(&lsm, 0) > 0 || remaining(&lsm, 1) > 0 {
+        lsm.put(&keys.next().unwrap(), "v").unwrap();
+        lsm.wait_for_background_work().unwrap();
+    }
+    assert_eq!(0, lsm.statistics().slowdown_writes);
+    //the merges take long, so the full buffer waits
+    let running = lsm.inner.background.lock().unwrap();
+    while lsm.inner.immutables.read().unwrap().is_empty() {
+        lsm.put(&keys.next().unwrap(), "v").unwrap();
+    }
+    lsm.put(&keys.next().unwrap(), "v").unwrap();
+    assert_eq!(1, lsm.statistics().slowdown_writes);
+    drop(running);
+    lsm.wait_for_background_work().unwrap();
+    //the merges went all the way down and level 0 has room again
+    assert!(!lsm.inner.levels.read().unwrap()[2].runs.is_empty());
+    assert!(remaining(&lsm, 0) > 0);
+    assert!(remaining(&lsm, 1) > 0);
+    lsm.put(&keys.next().unwrap(), "v").unwrap();
+    assert_eq!(1, lsm.statistics().slowdown_writes);
+}
+
+#[test]
+fn test_concurrent_readers() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<LSMTree>();
+
+    let _ = fs::remove_dir_all("/tmp/concurrent_readers_test");
+    let lsm =
+        Arc::new(LSMTree::open("/tmp/concurrent_readers_test", test_options(1024, 5, 3)).unwrap());
+    let key = |i: usize| format!("{:04}", i);
+    //keys below 200 never change, the writer adds the others while the readers run
+    for i in 0..200 {
+        lsm.put(&key(i), "fixed").unwrap();
+    }
+    let written = Arc::new(AtomicUsize::new(200));
+    let writer = {
+        let lsm = lsm.clone();
+        let written = written.clone();
+        thread::spawn(move || {
+            //enough to flush the buffer and merge runs many times over
+            for i in 200..1500 {
+                lsm.put(&key(i), &i.to_string()).unwrap();
+                written.store(i + 1, Ordering::Release);
+            }
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let lsm = lsm.clone();
+            let written = written.clone();
+            thread::spawn(move || {
+                let mut rng = thread_rng();
+                while written.load(Ordering::Acquire) < 1500 {
+                    let fixed = rng.gen_range(0, 200);
+                    assert_eq!(Some("fixed".to_string()), lsm.get(&key(fixed)).unwrap());
+                    //every write that finished is visible
+                    let last = written.load(Ordering::Acquire) - 1;
+                    assert!(lsm.get(&key(last)).unwrap().is_some());
+                    assert_eq!(200, lsm.range(&key(0), &key(199)).unwrap().len());
+                    assert!(lsm.iter().unwrap().count() > last);
+                }
+            })
+        })
+        .collect();
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    for i in 200..1500 {
+        assert_eq!(Some(i.to_string()), lsm.get(&key(i)).unwrap());
     }
 }
 
@@ -493,15 +2193,15 @@ fn bench_put() {
     let test_size = 100000;
     let mut rng = thread_rng();
     let mut data = Vec::new();
-    for i in 0..test_size {
+    for _ in 0..test_size {
         let key = rng.gen_range(1, 100000);
         data.push(key.to_string());
     }
 
-    let mut lsm = LSMTree::new(100000, 5, 10, 0.5, 4, "bench_put".to_string());
+    let lsm = LSMTree::new("bench_put", test_options(1 << 24, 5, 10)).unwrap();
     let start = Instant::now();
-    for i in 0..test_size {
-        lsm.put(&data[i], "test");
+    for key in data.iter() {
+        lsm.put(key, "test").unwrap();
     }
     let duration = start.elapsed();
     println!(
@@ -510,8 +2210,8 @@ fn bench_put() {
     );
 
     let start = Instant::now();
-    for i in 0..test_size {
-        lsm.get(&data[i]);
+    for key in data.iter() {
+        lsm.get(key).unwrap();
     }
     let duration = start.elapsed();
     println!(
@@ -521,8 +2221,8 @@ fn bench_put() {
 
     let mut hashmap: HashMap<&str, &str> = HashMap::new();
     let start = Instant::now();
-    for i in 0..test_size {
-        hashmap.insert(&data[i], "test");
+    for key in data.iter() {
+        hashmap.insert(key, "test");
     }
     let duration = start.elapsed();
     println!(
@@ -530,14 +2230,147 @@ fn bench_put() {
         test_size, duration
     );
 
-    lsm.clear();
+    lsm.clear().unwrap();
+}
+
+//the number of runs in every level
+#[cfg(test)]
+fn level_run_counts(lsm: &LSMTree) -> Vec<usize> {
+    let levels = lsm.inner.levels.read().unwrap();
+    levels.iter().map(|level| level.runs.len()).collect()
+}
+
+#[test]
+fn test_write_batch() {
+    use std::sync::atomic::AtomicBool;
+    let _ = fs::remove_dir_all("/tmp/write_batch_test");
+    let lsm = Arc::new(LSMTree::open("/tmp/write_batch_test", test_options(1024, 5, 3)).unwrap());
+    let mut batch = WriteBatch::new();
+    batch.put(b"index", b"0").put(b"record", b"0");
+    lsm.write(batch).unwrap();
+
+    //a reader never sees the index and the record of different batches, not even
+    //while their buffer is flushed or their runs merged
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let lsm = lsm.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::Acquire) {
+                let values: Vec<_> = lsm
+                    .range_iter(b"index", b"record")
+                    .unwrap()
+                    .map(|item| item.unwrap().1)
+                    .collect();
+                assert_eq!(2, values.len());
+                assert_eq!(values[0], values[1]);
+            }
+        })
+    };
+    for i in 1..300 {
+        let mut batch = WriteBatch::new();
+        let value = i.to_string();
+        batch.put(b"index", value.as_bytes());
+        batch.put(b"record", value.as_bytes());
+        batch.put(format!("filler{}", i).as_bytes(), b"");
+        lsm.write(batch).unwrap();
+    }
+    done.store(true, Ordering::Release);
+    reader.join().unwrap();
+
+    //a batch larger than the buffer still goes into one buffer as a whole
+    lsm.wait_for_background_work().unwrap();
+    //a full buffer is sealed before the batch goes in
+    let buffered = {
+        let buffer = lsm.inner.buffer.read().unwrap();
+        if buffer.approximate_memory_usage() as u64 >= lsm.options().write_buffer_size {
+            0
+        } else {
+            buffer.len()
+        }
+    };
+    let mut batch = WriteBatch::new();
+    for i in 0..20 {
+        batch.put(format!("big{:02}", i).as_bytes(), b"x");
+    }
+    batch.delete(b"filler1");
+    lsm.write(batch).unwrap();
+    assert_eq!(buffered + 21, lsm.inner.buffer.read().unwrap().len());
+    lsm.write(WriteBatch::new()).unwrap();
+
+    //simulate a crash: the batch is replayed from its log record, and the runs are
+    //loaded once
+    lsm.wait_for_background_work().unwrap();
+    let run_counts = level_run_counts(&lsm);
+    drop(Arc::try_unwrap(lsm).ok().unwrap());
+    let lsm = LSMTree::open("/tmp/write_batch_test", test_options(1024, 5, 3)).unwrap();
+    lsm.wait_for_background_work().unwrap();
+    assert_eq!(run_counts, level_run_counts(&lsm));
+    assert_eq!(Some(b"299".to_vec()), lsm.get_bytes(b"index").unwrap());
+    assert_eq!(Some(b"299".to_vec()), lsm.get_bytes(b"record").unwrap());
+    assert_eq!(Some(b"x".to_vec()), lsm.get_bytes(b"big19").unwrap());
+    assert_eq!(None, lsm.get_bytes(b"filler1").unwrap());
+    assert_eq!(Some(Vec::new()), lsm.get_bytes(b"filler2").unwrap());
+    lsm.close().unwrap();
+    assert!(matches!(lsm.write(WriteBatch::new()), Err(Error::Closed)));
+}
+
+#[test]
+fn test_parallel_writers() {
+    for (name, kind) in [
+        ("parallel_writers_btree", MemTableKind::BTree),
+        ("parallel_writers_skiplist", MemTableKind::SkipList),
+    ]
+    .iter()
+    {
+        let path = Path::new("/tmp").join(name);
+        let _ = fs::remove_dir_all(&path);
+        let options = Options {
+            memtable: *kind,
+            ..test_options(8192, 5, 3)
+        };
+        let lsm = Arc::new(LSMTree::open(&path, options).unwrap());
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let lsm = lsm.clone();
+                thread::spawn(move || {
+                    for i in 0..500 {
+                        lsm.put(&format!("{}-{:03}", t, i), &i.to_string()).unwrap();
+                        lsm.put("shared", &format!("{}-{}", t, i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        for t in 0..4 {
+            for i in 0..500 {
+                assert_eq!(
+                    Some(i.to_string()),
+                    lsm.get(&format!("{}-{:03}", t, i)).unwrap()
+                );
+            }
+        }
+        //the buffer holds the write of the shared key that came last in the log
+        let shared = lsm.get("shared").unwrap();
+        assert!(shared.is_some());
+        lsm.wait_for_background_work().unwrap();
+        let run_counts = level_run_counts(&lsm);
+        drop(Arc::try_unwrap(lsm).ok().unwrap());
+        let lsm = LSMTree::open(&path, options).unwrap();
+        lsm.wait_for_background_work().unwrap();
+        assert_eq!(run_counts, level_run_counts(&lsm));
+        assert_eq!(shared, lsm.get("shared").unwrap());
+        assert_eq!(Some("499".to_string()), lsm.get("3-499").unwrap());
+    }
 }
 
 // #[test]
 // fn test_multithreading() {
 //     let num_threads = 10;
 //     let test_size = 1000;
-//     let mut lsm = LSMTree::new(8, 5, 8, 0.5, 4, "clear_test".to_string());
+//     let mut lsm = LSMTree::new("clear_test", test_options(1024, 5, 8));
 //     for i in 0..test_size {
 //         lsm.put(&i.to_string(), &i.to_string());
 //     }
@@ -1,111 +1,132 @@
-use getopts::Options;
-use lsm_kv::data_type::ENTRY_SIZE;
+use getopts::{Matches, Options};
+use lsm_kv::error::{Error, Result};
 use lsm_kv::lsm;
 use lsm_kv::lsm::LSMTree;
+use lsm_kv::options;
 use std::io::BufRead;
-use std::{env, io};
+use std::str::FromStr;
+use std::{env, io, process};
 
-fn command_loop(lsm_tree: &mut LSMTree, input: impl BufRead) {
+//runs a single command. the storage errors are returned, everything else is printed
+fn run_command(lsm_tree: &LSMTree, tokens: &[&str]) -> Result<()> {
+    match tokens {
+        ["p", key, value] => {
+            lsm_tree.put(key, value)?;
+            println!("The k-v ({}, {}) has been inserted!", key, value);
+        }
+        ["g", key] => {
+            if let Some(val) = lsm_tree.get(key)? {
+                println!("The value of key {} is {}", key, val);
+            } else {
+                println!("No value with key {} in the DB!", key);
+            }
+        }
+        ["r", start, end] => {
+            let vals = lsm_tree.range(start, end)?;
+            if vals.is_empty() {
+                println!("No value with key between {} and {} in the DB!", start, end);
+            } else {
+                println!("Values with keys between {} and {} are:", start, end);
+                for val in vals {
+                    print!("{} ", val);
+                }
+                println!();
+            }
+        }
+        ["d", key] => {
+            lsm_tree.del(key)?;
+            println!("The k-v with key {} has been deleted!", key);
+        }
+        ["l", ..] => {}
+        _ => {
+            println!("Invalid command!");
+        }
+    }
+    Ok(())
+}
+
+fn command_loop(lsm_tree: &LSMTree, input: impl BufRead) {
     for line in input.lines() {
         match line {
             Ok(line) => {
                 let tokens: Vec<&str> = line.split_whitespace().collect();
                 if tokens.is_empty() {
                     continue;
-                } else {
-                    match tokens[0] {
-                        "p" => {
-                            lsm_tree.put(tokens[1], tokens[2]);
-                            println!("The k-v ({}, {}) has been inserted!", tokens[1], tokens[2]);
-                        }
-                        "g" => {
-                            let val = lsm_tree.get(tokens[1]);
-                            if val.is_some() {
-                                println!("The value of key {} is {}", tokens[1], val.unwrap());
-                            } else {
-                                println!("No value with key {} in the DB!", tokens[1]);
-                            }
-                        }
-                        "r" => {
-                            let vals = lsm_tree.range(tokens[1], tokens[2]);
-                            if vals.is_empty() {
-                                println!(
-                                    "No value with key between {} and {} in the DB!",
-                                    tokens[1], tokens[2]
-                                );
-                            } else {
-                                println!(
-                                    "Values with keys between {} and {} are:",
-                                    tokens[1], tokens[2]
-                                );
-                                for val in vals {
-                                    print!("{} ", val);
-                                }
-                                println!();
-                            }
-                        }
-                        "d" => {
-                            lsm_tree.del(tokens[1]);
-                            println!("The k-v with key {} has been deleted!", tokens[1]);
-                        }
-                        "l" => {}
-                        _ => {
-                            println!("Invalid command!");
-                        }
-                    }
                 }
+                if let Err(e) = run_command(lsm_tree, &tokens) {
+                    println!("Command failed: {}", e);
+                }
+            }
+            Err(e) => {
+                eprintln!("error {} during reading input", e);
+                return;
             }
-            Err(e) => panic!("error {} during reading input\n", e),
         }
     }
 }
 
+//the value of the command line option name, or default if it is not given
+fn parse_opt<T: FromStr>(matches: &Matches, name: &str, default: T) -> T {
+    match matches.opt_str(name) {
+        Some(val) => match val.parse() {
+            Ok(val) => val,
+            Err(_) => {
+                eprintln!("Invalid value {} for option -{}", val, name);
+                process::exit(1);
+            }
+        },
+        None => default,
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    let mut buffer_num_pages = lsm::DEFAULT_BUFFER_NUM_PAGES;
-    let mut depth = lsm::DEFAULT_TREE_DEPTH;
-    let mut fanout = lsm::DEFAULT_TREE_FANOUT;
-    let mut num_threads = lsm::DEFAULT_THREAD_COUNT;
-    let mut bf_bits_per_entry = lsm::DEFAULT_BF_BITS_PER_ENTRY;
-    let tree_name = lsm::DEFAULT_TREE_NAME;
-
     let mut opts = Options::new();
     opts.optopt("b", "", "number of pages in buffer", "PAGE_NUM");
     opts.optopt("d", "", "number of levels", "LEVEL_NUM");
     opts.optopt("f", "", "level fanout", "FANOUT");
     opts.optopt("t", "", "number of threads", "THREADS_NUM");
     opts.optopt("r", "", "bloom filter bits per entry", "BLOOM_BITS");
+    opts.optopt("p", "", "directory holding the data files", "PATH");
+    opts.optopt("c", "", "bytes of run pages to cache", "CACHE_BYTES");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(f) => panic!(f.to_string()),
+        Err(f) => {
+            eprintln!("{}", f);
+            process::exit(1);
+        }
     };
-    if matches.opt_str("b").is_some() {
-        buffer_num_pages = matches.opt_str("b").unwrap().parse().unwrap()
-    }
-    if matches.opt_str("d").is_some() {
-        depth = matches.opt_str("d").unwrap().parse().unwrap()
-    }
-    if matches.opt_str("f").is_some() {
-        fanout = matches.opt_str("f").unwrap().parse().unwrap()
-    }
-    if matches.opt_str("t").is_some() {
-        num_threads = matches.opt_str("t").unwrap().parse().unwrap()
-    }
-    if matches.opt_str("r").is_some() {
-        bf_bits_per_entry = matches.opt_str("r").unwrap().parse().unwrap()
-    }
+    let buffer_num_pages = parse_opt(&matches, "b", lsm::DEFAULT_BUFFER_NUM_PAGES);
+    let depth = parse_opt(&matches, "d", lsm::DEFAULT_TREE_DEPTH);
+    let fanout = parse_opt(&matches, "f", lsm::DEFAULT_TREE_FANOUT);
+    let num_threads = parse_opt(&matches, "t", lsm::DEFAULT_THREAD_COUNT);
+    let bf_bits_per_entry = parse_opt(&matches, "r", lsm::DEFAULT_BF_BITS_PER_ENTRY);
+    let block_cache_size = parse_opt(&matches, "c", lsm::DEFAULT_BLOCK_CACHE_SIZE);
+    let path = matches
+        .opt_str("p")
+        .unwrap_or_else(|| format!("/tmp/{}", lsm::DEFAULT_TREE_NAME));
 
-    let buffer_max_entries = buffer_num_pages * page_size::get() as u64 / ENTRY_SIZE as u64;
-
-    let mut lsm_tree = LSMTree::new(
-        buffer_max_entries,
-        depth,
-        fanout,
-        bf_bits_per_entry,
-        num_threads,
-        tree_name.to_string(),
-    );
+    let write_buffer_size = buffer_num_pages
+        .checked_mul(page_size::get() as u64)
+        .ok_or_else(|| Error::invalid_argument("the buffer pages must fit in a u64 of bytes"));
+    let options = write_buffer_size.and_then(|write_buffer_size| {
+        options::Options::builder()
+            .write_buffer_size(write_buffer_size)
+            .depth(depth)
+            .fanout(fanout)
+            .bf_bits_per_entry(bf_bits_per_entry)
+            .num_threads(num_threads)
+            .block_cache_size(block_cache_size)
+            .build()
+    });
+    let lsm_tree = match options.and_then(|options| LSMTree::open(&path, options)) {
+        Ok(tree) => tree,
+        Err(e) => {
+            eprintln!("Can not open the tree in {}: {}", path, e);
+            process::exit(1);
+        }
+    };
 
-    command_loop(&mut lsm_tree, io::stdin().lock())
+    command_loop(&lsm_tree, io::stdin().lock())
 }
@@ -0,0 +1,270 @@
+//the manifest is the log of every change to the set of runs in the tree. a flush adds a
+//run to level 0, a merge adds a run to the next level and removes the runs it merged.
+//replaying the log gives the runs of every level, newest first, and is the only source
+//of truth for load: run files that the manifest does not know about are left overs.
+use crate::coding::{put_u32, put_u64, Decoder};
+use crate::error::{Error, Result};
+use crate::wal::{decode_records, encode_record};
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+pub static MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunMeta {
+    pub file_number: u64,
+    //number of entries
+    pub size: u64,
+    //length of the run file
+    pub bytes: u64,
+}
+
+/// One atomic change to the runs of the tree
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VersionEdit {
+    pub next_file_number: u64,
+    //(level, run). every added run becomes the newest run of its level
+    pub added: Vec<(usize, RunMeta)>,
+    //(level, file number)
+    pub removed: Vec<(usize, u64)>,
+}
+
+impl VersionEdit {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        put_u64(&mut res, self.next_file_number);
+        put_u32(&mut res, self.added.len() as u32);
+        for (level, run) in self.added.iter() {
+            put_u32(&mut res, *level as u32);
+            put_u64(&mut res, run.file_number);
+            put_u64(&mut res, run.size);
+            put_u64(&mut res, run.bytes);
+        }
+        put_u32(&mut res, self.removed.len() as u32);
+        for (level, file_number) in self.removed.iter() {
+            put_u32(&mut res, *level as u32);
+            put_u64(&mut res, *file_number);
+        }
+        res
+    }
+
+    pub fn decode(data: &[u8]) -> Option<VersionEdit> {
+        let mut decoder = Decoder::new(data);
+        let mut edit = VersionEdit {
+            next_file_number: decoder.u64()?,
+            ..VersionEdit::default()
+        };
+        for _ in 0..decoder.u32()? {
+            let level = decoder.u32()? as usize;
+            let run = RunMeta {
+                file_number: decoder.u64()?,
+                size: decoder.u64()?,
+                bytes: decoder.u64()?,
+            };
+            edit.added.push((level, run));
+        }
+        for _ in 0..decoder.u32()? {
+            let level = decoder.u32()? as usize;
+            edit.removed.push((level, decoder.u64()?));
+        }
+        if !decoder.done() {
+            return None;
+        }
+        Some(edit)
+    }
+}
+
+/// The runs of every level, newest first, as recorded by the manifest
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ManifestState {
+    pub levels: Vec<VecDeque<RunMeta>>,
+    pub next_file_number: u64,
+}
+
+impl ManifestState {
+    pub fn apply(&mut self, edit: &VersionEdit) -> Result<()> {
+        for (level, file_number) in edit.removed.iter() {
+            let runs = match self.levels.get_mut(*level) {
+                Some(runs) => runs,
+                None => {
+                    return Err(Error::corruption(
+                        "manifest removes a run of an unknown level",
+                    ))
+                }
+            };
+            match runs.iter().position(|run| run.file_number == *file_number) {
+                Some(index) => {
+                    runs.remove(index);
+                }
+                None => return Err(Error::corruption("manifest removes an unknown run")),
+            }
+        }
+        for (level, run) in edit.added.iter() {
+            while self.levels.len() <= *level {
+                self.levels.push(VecDeque::new());
+            }
+            self.levels[*level].push_front(run.clone());
+        }
+        self.next_file_number = edit.next_file_number;
+        Ok(())
+    }
+
+    //a single edit that rebuilds this state from scratch
+    fn snapshot(&self) -> VersionEdit {
+        let mut edit = VersionEdit {
+            next_file_number: self.next_file_number,
+            ..VersionEdit::default()
+        };
+        for (level, runs) in self.levels.iter().enumerate() {
+            //added runs are pushed to the front, so add the oldest first
+            for run in runs.iter().rev() {
+                edit.added.push((level, run.clone()));
+            }
+        }
+        edit
+    }
+}
+
+pub struct Manifest {
+    path: PathBuf,
+    file: File,
+}
+
+impl Manifest {
+    /// Opens the manifest at `path`, creating an empty one if needed
+    pub fn open(path: &Path) -> Result<Manifest> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Ok(Manifest {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Replays every edit in the manifest. An edit torn by a crash never happened.
+    pub fn replay(&mut self) -> Result<ManifestState> {
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut data)?;
+        let mut state = ManifestState::default();
+        let (payloads, valid_len) = decode_records(&data);
+        for payload in payloads {
+            match VersionEdit::decode(payload) {
+                Some(edit) => state.apply(&edit)?,
+                None => return Err(Error::corruption("corrupted edit in manifest")),
+            }
+        }
+        if valid_len < data.len() {
+            self.file.set_len(valid_len as u64)?;
+        }
+        Ok(state)
+    }
+
+    /// Appends `edit` and syncs it. The change only counts once this returns.
+    pub fn log(&mut self, edit: &VersionEdit) -> Result<()> {
+        self.file.write_all(&encode_record(&edit.encode()))?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces the whole log by a single edit describing `state`, so that the
+    /// manifest does not keep growing across restarts.
+    pub fn rewrite(&mut self, state: &ManifestState) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&encode_record(&state.snapshot().encode()))?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        *self = Manifest::open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_edit_encode_decode() {
+    let edit = VersionEdit {
+        next_file_number: 12,
+        added: vec![(
+            1,
+            RunMeta {
+                file_number: 11,
+                size: 40,
+                bytes: 1234,
+            },
+        )],
+        removed: vec![(0, 3), (0, 4)],
+    };
+    assert_eq!(Some(edit.clone()), VersionEdit::decode(&edit.encode()));
+    let encoded = edit.encode();
+    assert_eq!(None, VersionEdit::decode(&encoded[..encoded.len() - 1]));
+}
+
+#[test]
+fn test_replay_rewrite() {
+    let _ = fs::create_dir_all("/tmp/manifest_unit_test");
+    let path = Path::new("/tmp/manifest_unit_test/MANIFEST");
+    let _ = fs::remove_file(path);
+    let run = |file_number: u64| RunMeta {
+        file_number,
+        size: file_number * 10,
+        bytes: file_number * 100,
+    };
+    let mut manifest = Manifest::open(path).unwrap();
+    for file_number in 0..3 {
+        manifest
+            .log(&VersionEdit {
+                next_file_number: file_number + 1,
+                added: vec![(0, run(file_number))],
+                removed: vec![],
+            })
+            .unwrap();
+    }
+    //merge level 0 into level 1
+    manifest
+        .log(&VersionEdit {
+            next_file_number: 4,
+            added: vec![(1, run(3))],
+            removed: vec![(0, 0), (0, 1), (0, 2)],
+        })
+        .unwrap();
+    for file_number in 4..6 {
+        manifest
+            .log(&VersionEdit {
+                next_file_number: file_number + 1,
+                added: vec![(0, run(file_number))],
+                removed: vec![],
+            })
+            .unwrap();
+    }
+    //a torn edit is ignored
+    (&manifest.file).write_all(&[1, 2, 3]).unwrap();
+
+    let mut manifest = Manifest::open(path).unwrap();
+    let state = manifest.replay().unwrap();
+    assert_eq!(6, state.next_file_number);
+    assert_eq!(
+        vec![
+            VecDeque::from(vec![run(5), run(4)]),
+            VecDeque::from(vec![run(3)])
+        ],
+        state.levels
+    );
+
+    manifest.rewrite(&state).unwrap();
+    let mut manifest = Manifest::open(path).unwrap();
+    assert_eq!(state, manifest.replay().unwrap());
+}
@@ -0,0 +1,130 @@
+//the in-memory table that takes the writes of the tree until it is flushed to a run.
+//writes are ordered by a sequence number the tree hands out when it logs them, so
+//inserts running in parallel can land in any order and the newest write still wins.
+use crate::buffer::Buffer;
+use crate::data_type::{EntryT, KeyT};
+use crate::skiplist::SkipList;
+use std::ops::Bound;
+use std::ops::Bound::Unbounded;
+
+/// A table of the newest writes, kept in key order
+pub trait MemTable: Send + Sync {
+    /// Returns the entry of `key` with the highest sequence number, which may be a tombstone
+    fn get(&self, key: &KeyT) -> Option<EntryT>;
+
+    /// Inserts `entry`, written with sequence number `seq`. Of the entries of one key the
+    /// one with the highest sequence number wins, whatever order they were put in.
+    fn put(&self, entry: EntryT, seq: u64);
+
+    /// The winning entry of every key between the bounds, in key order
+    fn range(
+        &self,
+        start: Bound<&KeyT>,
+        end: Bound<&KeyT>,
+    ) -> Box<dyn Iterator<Item = EntryT> + '_>;
+
+    /// The entry of the first key within `start` that has one written with a sequence
+    /// number below `seq`, the one with the highest such number. Writes from `seq` on are
+    /// not seen, so a reader can walk the table while writes go on.
+    fn first_at(&self, start: Bound<&KeyT>, seq: u64) -> Option<EntryT>;
+
+    /// Like first_at, for the last key within `end`
+    fn last_at(&self, end: Bound<&KeyT>, seq: u64) -> Option<EntryT>;
+
+    /// One above the highest sequence number put so far
+    fn sequence(&self) -> u64;
+
+    /// Number of entries held. Entries of a key that lost to a newer one may still count.
+    fn len(&self) -> usize;
+
+    /// Bytes taken by the entries and the structure holding them
+    fn approximate_memory_usage(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The winning entry of every key, in key order
+    fn iter(&self) -> Box<dyn Iterator<Item = EntryT> + '_> {
+        self.range(Unbounded, Unbounded)
+    }
+}
+
+/// The memtable implementations a tree can use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemTableKind {
+    /// A `BTreeSet` behind a lock, inserts run one at a time
+    BTree,
+    /// A lock-free skiplist, inserts run in parallel
+    SkipList,
+}
+
+impl MemTableKind {
+    pub fn new_memtable(self) -> Box<dyn MemTable> {
+        match self {
+            MemTableKind::BTree => Box::new(Buffer::new()),
+            MemTableKind::SkipList => Box::new(SkipList::new()),
+        }
+    }
+}
+
+#[test]
+fn test_memtables() {
+    use std::ops::Bound::{Excluded, Included};
+    for kind in [MemTableKind::BTree, MemTableKind::SkipList].iter() {
+        let table = kind.new_memtable();
+        assert!(table.is_empty());
+        //put out of order, the highest sequence number wins
+        table.put(EntryT::new(b"b".to_vec(), b"2".to_vec()), 2);
+        table.put(EntryT::new(b"b".to_vec(), b"1".to_vec()), 1);
+        table.put(EntryT::new(b"a".to_vec(), b"0".to_vec()), 0);
+        table.put(EntryT::tombstone(b"c".to_vec()), 4);
+        table.put(EntryT::new(b"c".to_vec(), b"3".to_vec()), 3);
+        assert_eq!(b"2".to_vec(), table.get(&b"b".to_vec()).unwrap().value);
+        assert!(table.get(&b"c".to_vec()).unwrap().is_tombstone());
+        assert!(table.get(&b"d".to_vec()).is_none());
+        assert!(table.approximate_memory_usage() > 0);
+
+        let keys = |entries: Vec<EntryT>| -> Vec<KeyT> {
+            entries.into_iter().map(|entry| entry.key).collect()
+        };
+        let all: Vec<EntryT> = table.iter().collect();
+        assert_eq!(
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()],
+            keys(all.clone())
+        );
+        assert_eq!(b"2".to_vec(), all[1].value);
+        let (a, b) = (b"a".to_vec(), b"b".to_vec());
+        assert_eq!(
+            vec![b"b".to_vec()],
+            keys(table.range(Excluded(&a), Included(&b)).collect())
+        );
+        assert_eq!(
+            vec![b"a".to_vec()],
+            keys(table.range(Unbounded, Excluded(&b)).collect())
+        );
+        assert_eq!(
+            vec![b"b".to_vec(), b"c".to_vec()],
+            keys(table.range(Included(&b), Unbounded).collect())
+        );
+
+        //reads as of a sequence number skip the writes from it on
+        let c = b"c".to_vec();
+        assert_eq!(5, table.sequence());
+        assert_eq!(a, table.first_at(Unbounded, 5).unwrap().key);
+        assert_eq!(
+            b"1".to_vec(),
+            table.first_at(Excluded(&a), 2).unwrap().value
+        );
+        assert!(table.first_at(Included(&c), 3).is_none());
+        assert_eq!(
+            b"3".to_vec(),
+            table.first_at(Included(&c), 4).unwrap().value
+        );
+        assert!(table.last_at(Unbounded, 5).unwrap().is_tombstone());
+        assert_eq!(b"2".to_vec(), table.last_at(Unbounded, 3).unwrap().value);
+        assert_eq!(a, table.last_at(Excluded(&b), 5).unwrap().key);
+        assert_eq!(b"1".to_vec(), table.last_at(Included(&b), 2).unwrap().value);
+        assert!(table.last_at(Unbounded, 0).is_none());
+    }
+}
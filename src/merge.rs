@@ -4,7 +4,7 @@ use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 //use std::hash::{Hash, Hasher};
 use std::str;
-#[derive(Eq, Debug, Hash, Clone)]
+#[derive(Eq, Debug, Clone)]
 struct MergeEntry {
     pub precedence: usize,
     pub entries: Vec<EntryT>,
@@ -60,6 +60,7 @@ impl PartialEq for MergeEntry {
 
 type MergeEntryT = MergeEntry;
 
+#[derive(Default)]
 pub struct MergeContext {
     //priority_queue: PriorityQueue<MergeEntryT, MergeEntryT>,
     priority_queue: BinaryHeap<MergeEntryT>,
@@ -83,13 +84,32 @@ impl MergeContext {
         }
     }
 
-    pub fn next(&mut self) -> EntryT {
+    pub fn print(&mut self) {
+        println!("merge ctx print start");
+        for tmp in &self.priority_queue {
+            for entry in tmp.entries.iter() {
+                println!("{}", str::from_utf8(&entry.value).unwrap());
+            }
+        }
+        //println!("{:?}", str::from_utf8(&self.priority_queue.peek().unwrap().entries[0].value));
+    }
+
+    pub fn done(&self) -> bool {
+        self.priority_queue.is_empty()
+    }
+}
+
+impl Iterator for MergeContext {
+    type Item = EntryT;
+
+    //return the entry with the smallest key. If several runs hold that key, the one with the lowest precedence wins.
+    fn next(&mut self) -> Option<EntryT> {
         //TODO priority_queue return both item and its priority
         let mut next: MergeEntryT;
-        let current = self.priority_queue.peek().unwrap().clone();
+        let current = self.priority_queue.peek()?.head();
         //println!("{}", str::from_utf8(&current.head().value).unwrap());
         while !self.priority_queue.is_empty()
-            && self.priority_queue.peek().unwrap().head().key == current.head().key
+            && self.priority_queue.peek().unwrap().head().key == current.key
         {
             next = self.priority_queue.pop().unwrap();
             next.current_index += 1;
@@ -98,21 +118,7 @@ impl MergeContext {
             }
         }
         //println!("{}", str::from_utf8(&current.head().value).unwrap());
-        current.head()
-    }
-
-    pub fn print(&mut self) {
-        println!("merge ctx print start");
-        for tmp in &self.priority_queue{
-            for entry in tmp.entries.iter() {
-                println!("{}", str::from_utf8(&entry.value).unwrap());
-            }
-        }
-        //println!("{:?}", str::from_utf8(&self.priority_queue.peek().unwrap().entries[0].value));
-    }
-
-    pub fn done(&self) -> bool {
-        self.priority_queue.is_empty()
+        Some(current)
     }
 }
 
@@ -122,3 +128,29 @@ pub type MergeContextT = MergeContext;
 fn test_merge_entry_cmp() {
     println!("hello merge");
 }
+
+#[test]
+fn test_merge_precedence() {
+    let newer = vec![
+        EntryT::new(b"b".to_vec(), b"new".to_vec()),
+        EntryT::new(b"dddddddd".to_vec(), b"new".to_vec()),
+    ];
+    let older = vec![
+        EntryT::new(b"a".to_vec(), b"old".to_vec()),
+        EntryT::new(b"b".to_vec(), b"old value".to_vec()),
+        EntryT::new(b"c".to_vec(), b"old".to_vec()),
+    ];
+    let mut merge_ctx = MergeContext::new();
+    merge_ctx.add(newer, 2);
+    merge_ctx.add(older, 3);
+    let merged: Vec<(Vec<u8>, Vec<u8>)> = merge_ctx.map(|e| (e.key, e.value)).collect();
+    assert_eq!(
+        vec![
+            (b"a".to_vec(), b"old".to_vec()),
+            (b"b".to_vec(), b"new".to_vec()),
+            (b"c".to_vec(), b"old".to_vec()),
+            (b"dddddddd".to_vec(), b"new".to_vec()),
+        ],
+        merged
+    );
+}
@@ -0,0 +1,345 @@
+use crate::error::{Error, Result};
+use crate::lsm::{
+    DEFAULT_BF_BITS_PER_ENTRY, DEFAULT_BLOCK_CACHE_SIZE, DEFAULT_BUFFER_NUM_PAGES,
+    DEFAULT_MEMTABLE, DEFAULT_PREFIX_LEN, DEFAULT_SLOWDOWN_WRITES_IMMUTABLES,
+    DEFAULT_STOP_WRITES_IMMUTABLES, DEFAULT_SYNC_POLICY, DEFAULT_THREAD_COUNT, DEFAULT_TREE_DEPTH,
+    DEFAULT_TREE_FANOUT,
+};
+use crate::memtable::MemTableKind;
+use crate::wal::SyncPolicy;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+//the settings that shape the files of a tree are saved in this file when the tree is created
+pub static OPTIONS_FILE_NAME: &str = "OPTIONS";
+
+/// Settings of a LSM tree
+///
+/// `write_buffer_size`, `depth`, `fanout`, `bf_bits_per_entry` and `prefix_len` shape the
+/// files of the tree. They are saved when the tree is created, and reopening the tree
+/// always uses the saved values. `num_threads`, `sync_policy`, `block_cache_size`, the
+/// write stall thresholds and `memtable` can change every time it is opened.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Options {
+    /// Bytes of memory the buffer takes before it is flushed. Runs of level `i` are
+    /// sized to `write_buffer_size * fanout^i` bytes.
+    pub write_buffer_size: u64,
+    /// depth of LSM tree
+    pub depth: u64,
+    /// A factor that determines how to scale Run size for deeper levels
+    pub fanout: u64,
+    /// Used for bloom filter size initialization
+    pub bf_bits_per_entry: f32,
+    /// Length of the key prefixes kept in a prefix filter of every run, 0 for none
+    pub prefix_len: u64,
+    /// Used for thread pool initialization
+    pub num_threads: u64,
+    /// How writes are synced to the write-ahead log unless they carry their own policy
+    pub sync_policy: SyncPolicy,
+    /// Bytes of run pages kept in the block cache of the tree, 0 for no cache
+    pub block_cache_size: u64,
+    /// Number of full buffers waiting for their flush at which every write is delayed
+    pub slowdown_writes_immutables: u64,
+    /// Number of full buffers waiting for their flush at which writes wait for a flush
+    pub stop_writes_immutables: u64,
+    /// The memtable implementation taking the writes
+    pub memtable: MemTableKind,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            write_buffer_size: DEFAULT_BUFFER_NUM_PAGES * page_size::get() as u64,
+            depth: DEFAULT_TREE_DEPTH,
+            fanout: DEFAULT_TREE_FANOUT,
+            bf_bits_per_entry: DEFAULT_BF_BITS_PER_ENTRY,
+            prefix_len: DEFAULT_PREFIX_LEN,
+            num_threads: DEFAULT_THREAD_COUNT,
+            sync_policy: DEFAULT_SYNC_POLICY,
+            block_cache_size: DEFAULT_BLOCK_CACHE_SIZE,
+            slowdown_writes_immutables: DEFAULT_SLOWDOWN_WRITES_IMMUTABLES,
+            stop_writes_immutables: DEFAULT_STOP_WRITES_IMMUTABLES,
+            memtable: DEFAULT_MEMTABLE,
+        }
+    }
+}
+
+impl Options {
+    /// Returns a builder starting from the default options
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::options::Options;
+    /// let options = Options::builder().fanout(4).depth(6).build().unwrap();
+    /// assert_eq!(4, options.fanout);
+    /// assert!(Options::builder().fanout(1).build().is_err());
+    /// ```
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder {
+            options: Options::default(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.write_buffer_size == 0 {
+            return Err(Error::invalid_argument("write_buffer_size must not be 0"));
+        }
+        if self.depth == 0 {
+            return Err(Error::invalid_argument("depth must not be 0"));
+        }
+        if self.fanout < 2 {
+            return Err(Error::invalid_argument("fanout must be at least 2"));
+        }
+        //the last level holds fanout runs of write_buffer_size * fanout^(depth-1) bytes
+        let last_level_size = u32::try_from(self.depth)
+            .ok()
+            .and_then(|depth| self.fanout.checked_pow(depth))
+            .and_then(|runs| runs.checked_mul(self.write_buffer_size));
+        if last_level_size.is_none() {
+            return Err(Error::invalid_argument(
+                "write_buffer_size * fanout^depth must fit in a u64",
+            ));
+        }
+        if !self.bf_bits_per_entry.is_finite() || self.bf_bits_per_entry <= 0.0 {
+            return Err(Error::invalid_argument("bf_bits_per_entry must be above 0"));
+        }
+        if self.num_threads == 0 {
+            return Err(Error::invalid_argument("num_threads must not be 0"));
+        }
+        //writes have to go on until the buffer is full again
+        if self.stop_writes_immutables == 0 {
+            return Err(Error::invalid_argument(
+                "stop_writes_immutables must not be 0",
+            ));
+        }
+        if self.slowdown_writes_immutables > self.stop_writes_immutables {
+            return Err(Error::invalid_argument(
+                "slowdown_writes_immutables must not be above stop_writes_immutables",
+            ));
+        }
+        Ok(())
+    }
+
+    //one "name=value" line per saved setting
+    fn encode(&self) -> String {
+        format!(
+            "write_buffer_size={}\ndepth={}\nfanout={}\nbf_bits_per_entry={}\nprefix_len={}\n",
+            self.write_buffer_size,
+            self.depth,
+            self.fanout,
+            self.bf_bits_per_entry,
+            self.prefix_len
+        )
+    }
+
+    //saved settings replace the ones in self. every one of them has to be there.
+    fn decode_into(&mut self, text: &str) -> Result<()> {
+        let corrupted = || Error::corruption("corrupted OPTIONS file");
+        let mut found = HashSet::new();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let mut parts = line.splitn(2, '=');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => return Err(corrupted()),
+            };
+            match name {
+                "write_buffer_size" => {
+                    self.write_buffer_size = value.parse().map_err(|_| corrupted())?
+                }
+                "depth" => self.depth = value.parse().map_err(|_| corrupted())?,
+                "fanout" => self.fanout = value.parse().map_err(|_| corrupted())?,
+                "bf_bits_per_entry" => {
+                    self.bf_bits_per_entry = value.parse().map_err(|_| corrupted())?
+                }
+                "prefix_len" => self.prefix_len = value.parse().map_err(|_| corrupted())?,
+                _ => return Err(corrupted()),
+            }
+            found.insert(name);
+        }
+        //the settings encode writes
+        if found.len() != 5 {
+            return Err(corrupted());
+        }
+        Ok(())
+    }
+
+    /// Returns the options a tree in `dir` has to be opened with: the saved settings of an
+    /// existing tree, or these options, which are then saved for a new tree.
+    pub fn load_or_save(&self, dir: &Path) -> Result<Options> {
+        let path = dir.join(OPTIONS_FILE_NAME);
+        let mut options = *self;
+        if path.exists() {
+            options.decode_into(&fs::read_to_string(&path)?)?;
+            options.validate()?;
+        } else {
+            options.validate()?;
+            //write the whole file under a temporary name first, so it is never seen half written
+            let tmp_path = path.with_extension("tmp");
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(options.encode().as_bytes())?;
+            tmp.sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+        }
+        Ok(options)
+    }
+}
+
+/// Builds validated `Options`
+#[derive(Clone, Copy, Debug)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn write_buffer_size(mut self, write_buffer_size: u64) -> Self {
+        self.options.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    pub fn depth(mut self, depth: u64) -> Self {
+        self.options.depth = depth;
+        self
+    }
+
+    pub fn fanout(mut self, fanout: u64) -> Self {
+        self.options.fanout = fanout;
+        self
+    }
+
+    pub fn bf_bits_per_entry(mut self, bf_bits_per_entry: f32) -> Self {
+        self.options.bf_bits_per_entry = bf_bits_per_entry;
+        self
+    }
+
+    pub fn prefix_len(mut self, prefix_len: u64) -> Self {
+        self.options.prefix_len = prefix_len;
+        self
+    }
+
+    pub fn num_threads(mut self, num_threads: u64) -> Self {
+        self.options.num_threads = num_threads;
+        self
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.options.sync_policy = sync_policy;
+        self
+    }
+
+    pub fn block_cache_size(mut self, block_cache_size: u64) -> Self {
+        self.options.block_cache_size = block_cache_size;
+        self
+    }
+
+    pub fn slowdown_writes_immutables(mut self, slowdown_writes_immutables: u64) -> Self {
+        self.options.slowdown_writes_immutables = slowdown_writes_immutables;
+        self
+    }
+
+    pub fn stop_writes_immutables(mut self, stop_writes_immutables: u64) -> Self {
+        self.options.stop_writes_immutables = stop_writes_immutables;
+        self
+    }
+
+    pub fn memtable(mut self, memtable: MemTableKind) -> Self {
+        self.options.memtable = memtable;
+        self
+    }
+
+    pub fn build(self) -> Result<Options> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+#[test]
+fn test_validate() {
+    assert!(Options::builder().build().is_ok());
+    assert!(Options::builder().write_buffer_size(0).build().is_err());
+    assert!(Options::builder().depth(0).build().is_err());
+    assert!(Options::builder().fanout(1).build().is_err());
+    assert!(Options::builder().fanout(2).build().is_ok());
+    //the sizes of the deepest levels would overflow
+    assert!(Options::builder().depth(20).build().is_err());
+    assert!(Options::builder()
+        .write_buffer_size(u64::MAX / 8)
+        .fanout(2)
+        .depth(3)
+        .build()
+        .is_ok());
+    assert!(Options::builder()
+        .write_buffer_size(u64::MAX / 8 + 1)
+        .fanout(2)
+        .depth(3)
+        .build()
+        .is_err());
+    assert!(Options::builder().num_threads(0).build().is_err());
+    assert!(Options::builder()
+        .stop_writes_immutables(0)
+        .build()
+        .is_err());
+    assert!(Options::builder()
+        .slowdown_writes_immutables(3)
+        .stop_writes_immutables(2)
+        .build()
+        .is_err());
+    assert!(Options::builder()
+        .slowdown_writes_immutables(0)
+        .stop_writes_immutables(1)
+        .build()
+        .is_ok());
+    assert!(Options::builder().bf_bits_per_entry(0.0).build().is_err());
+    assert!(Options::builder()
+        .bf_bits_per_entry(f32::NAN)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn test_load_or_save() {
+    let dir = Path::new("/tmp/options_unit_test");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+    let created = Options::builder()
+        .write_buffer_size(4096)
+        .depth(3)
+        .fanout(3)
+        .bf_bits_per_entry(1.5)
+        .prefix_len(4)
+        .build()
+        .unwrap();
+    assert_eq!(created, created.load_or_save(dir).unwrap());
+
+    let reopened = Options::builder()
+        .num_threads(2)
+        .sync_policy(SyncPolicy::EveryWrite)
+        .build()
+        .unwrap()
+        .load_or_save(dir)
+        .unwrap();
+    assert_eq!(4096, reopened.write_buffer_size);
+    assert_eq!(3, reopened.depth);
+    assert_eq!(3, reopened.fanout);
+    assert_eq!(1.5, reopened.bf_bits_per_entry);
+    assert_eq!(4, reopened.prefix_len);
+    assert_eq!(2, reopened.num_threads);
+    assert_eq!(SyncPolicy::EveryWrite, reopened.sync_policy);
+
+    //a setting missing, one that is not saved, and one that does not parse
+    let saved = created.encode();
+    let missing = saved.replace("prefix_len=4\n", "");
+    let unknown = format!("{}buf_max_entries=64\n", missing);
+    let bad = saved.replace("fanout=3", "fanout=ten");
+    for text in [missing, unknown, bad].iter() {
+        fs::write(dir.join(OPTIONS_FILE_NAME), text).unwrap();
+        assert!(matches!(
+            created.load_or_save(dir),
+            Err(Error::Corruption(_))
+        ));
+    }
+}
@@ -1,354 +1,1087 @@
-use crate::data_type::{EntryT, KeyT, ValueT, ENTRY_SIZE, KEY_SIZE, VALUE_SIZE};
-use libc;
+use crate::cache::{self, BlockCache};
+use crate::coding::{put_bytes, put_u32, put_u64, Decoder};
+use crate::data_type::{decode_key, EntryT, KeyT, ENTRY_HEADER_SIZE};
+#[cfg(test)]
+use crate::data_type::{EntryKind, ValueT};
+use crate::error::{Error, Result};
+use crate::manifest::RunMeta;
+use crate::wal::crc32;
 use memmap::{Mmap, MmapMut, MmapOptions};
-use mktemp::Temp;
-use mmap::{MapOption, MemoryMap};
 use page_size;
-use std::cmp::max;
-use std::io::prelude::*;
-//use std::collections::linked_list::Iter;
+use std::cmp::{max, min};
+use std::convert::TryInto;
+use std::fs;
 use std::fs::{File, OpenOptions};
-use std::os::raw;
-use std::os::raw::c_void;
-use std::os::unix::prelude::*;
-use std::path::PathBuf;
-use std::str;
-use std::sync::RwLock;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/*
+ * A run file holds pages of encoded entries, followed by a filter block with the bloom filter
+ * (and the prefix filter, if the run has one),
+ * an index block with the fence pointers and sizes, and a fixed size footer that locates
+ * and checksums both blocks. Reopening a run only reads the footer and the two blocks.
+ * Every page ends with a trailer holding the offset of each of its entries from the start
+ * of the page, then their number, all as u32, so a page can be binary searched in place.
+ */
+//filter offset, filter len, index offset, index len as u64, filter crc, index crc as u32, magic as u64
+pub static FOOTER_SIZE: usize = 48;
+//changes whenever the layout of run files does
+pub static RUN_MAGIC: u64 = 0x6c73_6d6b_7672_6e32;
+//size of an entry offset and of the entry count in a page trailer
+static TRAILER_SLOT_SIZE: usize = 4;
+
+//a page of a sealed run, borrowed from its mapping
+struct Page<'a> {
+    //encoded entries
+    data: &'a [u8],
+    //offset of every entry in data as u32
+    offsets: &'a [u8],
+    //where the page starts in the run file, for error messages
+    file_offset: usize,
+}
+
+impl<'a> Page<'a> {
+    //split the entries from the trailer of a page
+    fn parse(page: &'a [u8], file_offset: usize) -> Option<Page<'a>> {
+        let count_start = page.len().checked_sub(TRAILER_SLOT_SIZE)?;
+        let count = u32::from_le_bytes(page[count_start..].try_into().unwrap()) as usize;
+        let offsets_start = count_start.checked_sub(count.checked_mul(TRAILER_SLOT_SIZE)?)?;
+        Some(Page {
+            data: &page[..offsets_start],
+            offsets: &page[offsets_start..count_start],
+            file_offset,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.offsets.len() / TRAILER_SLOT_SIZE
+    }
+
+    fn offset(&self, index: usize) -> usize {
+        let slot = &self.offsets[index * TRAILER_SLOT_SIZE..(index + 1) * TRAILER_SLOT_SIZE];
+        u32::from_le_bytes(slot.try_into().unwrap()) as usize
+    }
+
+    //the key of entry index, without copying it
+    fn key(&self, index: usize) -> Option<&'a [u8]> {
+        decode_key(self.data.get(self.offset(index)..)?)
+    }
+
+    fn entry(&self, index: usize) -> Option<EntryT> {
+        EntryT::decode(self.data.get(self.offset(index)..)?).map(|(entry, _)| entry)
+    }
+
+    //index of the first entry with a key >= key, or len if there is none
+    fn lower_bound(&self, key: &[u8]) -> Option<usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.key(mid)? < key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Some(low)
+    }
+}
 
 pub struct Run {
     pub bloom_filter: bloomfilter::Bloom<KeyT>,
     //bloom_filer: bloom_filter::BloomFilter,
+    //holds the first prefix_len bytes of every key at least that long, if prefix_len > 0
+    pub prefix_filter: Option<bloomfilter::Bloom<[u8]>>,
+    pub prefix_len: usize,
+    //first key of every page
     pub fence_pointers: Vec<KeyT>,
+    //byte offset in the run file where every page starts
+    pub page_offsets: Vec<u64>,
     pub max_key: KeyT,
+    //only while the run is written
     pub mapping: Option<MmapMut>,
     pub mapping_file: Option<File>,
+    //offsets of the entries of the page being written, from the start of the page
+    page_entry_offsets: Vec<u32>,
+    //number of entries
     pub size: u64,
+    //number of bytes of pages, entries and their trailers
+    pub bytes: u64,
+    //length of the run file, entries and metadata blocks
+    pub file_size: u64,
     pub max_size: u64,
     pub tmp_file: PathBuf,
+    //unique among all runs of the tree, recorded in the manifest
+    pub file_number: u64,
     pub level_index: usize,
-    pub read_write_lock: RwLock<usize>,
+    //read-only mapping of the entries of a sealed run, which every lookup reads from.
+    //lookups share it, sealing and loading the run replace it.
+    pub read_write_lock: RwLock<Option<Mmap>>,
+    //identifies the pages of this run in the block cache
+    pub id: u64,
+    //where read_page looks for pages first, if the tree has a cache
+    pub block_cache: Option<Arc<BlockCache>>,
 }
 
 impl Run {
-    // pub fn new(max_size: u64, bf_bits_per_entry: f32) -> Run {
-    //     Run {
-    //         bloom_filter: bloomfilter::Bloom::new(
-    //             (bf_bits_per_entry * max_size as f32) as usize,
-    //             max_size as usize,
-    //         ),
-    //         //bloom_filer: bloom_filter::BloomFilter::new_with_size(max_size * bf_bits_per_entry),
-    //         fence_pointers: Vec::with_capacity((max_size / page_size::get() as u64) as usize),
-    //         max_key: KeyT::default(),
-    //         mapping: None,
-    //         mapping_fd: -1,
-    //         size: 0,
-    //         max_size: max_size,
-    //         tmp_file: Temp::new_file_in("/tmp/").unwrap().as_ref().to_owned(),
-    //     }
-    // }
-
     pub fn new(
         max_size: u64,
         bf_bits_per_entry: f32,
-        lsm_name: &str,
+        prefix_len: usize,
+        dir: &Path,
         level: usize,
-        id: usize,
+        file_number: u64,
     ) -> Run {
-        Run {
-            bloom_filter: bloomfilter::Bloom::new(
-                (bf_bits_per_entry * max_size as f32) as usize,
-                max_size as usize,
-            ),
-            //bloom_filer: bloom_filter::BloomFilter::new_with_size(max_size * bf_bits_per_entry),
-            fence_pointers: Vec::with_capacity((max_size / page_size::get() as u64) as usize),
-            max_key: KeyT::default(),
-            mapping: None,
-            mapping_file: None,
-            size: 0,
-            max_size: max_size,
-            level_index: level,
-            tmp_file: PathBuf::from(format!(r"/tmp/{}/{}/run_file-{}.txt", lsm_name, level, id)),
-            read_write_lock: RwLock::new(0),
-        }
+        Run::from(
+            max_size,
+            bf_bits_per_entry,
+            prefix_len,
+            level,
+            file_number,
+            dir.join(level.to_string())
+                .join(format!("run_file-{}.txt", file_number)),
+        )
     }
 
-    pub fn from(max_size: u64, bf_bits_per_entry: f32, level: usize, file_path: PathBuf) -> Run {
+    pub fn from(
+        max_size: u64,
+        bf_bits_per_entry: f32,
+        prefix_len: usize,
+        level: usize,
+        file_number: u64,
+        file_path: PathBuf,
+    ) -> Run {
+        //the filters of a run that holds a single entry still need a bit
+        let items = max(max_size as usize, 1);
+        let bitmap_size = max((bf_bits_per_entry * max_size as f32) as usize, 1);
         Run {
-            bloom_filter: bloomfilter::Bloom::new(
-                (bf_bits_per_entry * max_size as f32) as usize,
-                max_size as usize,
-            ),
+            bloom_filter: bloomfilter::Bloom::new(bitmap_size, items),
             //bloom_filer: bloom_filter::BloomFilter::new_with_size(max_size * bf_bits_per_entry),
-            fence_pointers: Vec::with_capacity((max_size / page_size::get() as u64) as usize),
+            prefix_filter: if prefix_len > 0 {
+                Some(bloomfilter::Bloom::new(bitmap_size, items))
+            } else {
+                None
+            },
+            prefix_len,
+            fence_pointers: Vec::new(),
+            page_offsets: Vec::new(),
             max_key: KeyT::default(),
             mapping: None,
             mapping_file: None,
+            page_entry_offsets: Vec::new(),
             size: 0,
-            max_size: max_size,
+            bytes: 0,
+            file_size: 0,
+            max_size,
             level_index: level,
             tmp_file: file_path,
-            read_write_lock: RwLock::new(0),
+            file_number,
+            read_write_lock: RwLock::new(None),
+            id: cache::new_run_id(),
+            block_cache: None,
         }
     }
 
-    pub fn map_read_default(&mut self) -> Vec<EntryT> {
-        self.map_read(ENTRY_SIZE * self.max_size as usize, 0)
+    pub fn meta(&self) -> RunMeta {
+        RunMeta {
+            file_number: self.file_number,
+            size: self.size,
+            bytes: self.file_size,
+        }
     }
 
-    pub fn map_read(&mut self, len: usize, offset: usize) -> Vec<EntryT> {
-        //assert!(self.mapping.is_none());
+    //offset and length in bytes of the page page_index
+    fn page_bounds(&self, page_index: usize) -> (usize, usize) {
+        let start = self.page_offsets[page_index];
+        let end = match self.page_offsets.get(page_index + 1) {
+            Some(next) => *next,
+            None => self.bytes,
+        };
+        (start as usize, (end - start) as usize)
+    }
 
-        match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(self.tmp_file.as_path())
-        {
-            Ok(file) => {
-                self.mapping_file = Some(file);
+    //decode every entry of a sealed run
+    pub fn map_read_default(&self) -> Result<Vec<EntryT>> {
+        let mut res = Vec::with_capacity(self.size as usize);
+        for page_index in 0..self.num_pages() {
+            res.extend(self.decode_page(page_index)?);
+        }
+        Ok(res)
+    }
+
+    //page page_index of mapping, the read mapping of this run
+    fn page<'m>(&self, mapping: &'m Option<Mmap>, page_index: usize) -> Result<Page<'m>> {
+        let (offset, len) = self.page_bounds(page_index);
+        let mapping = mapping.as_ref().expect("run is not sealed");
+        self.parse_page(&mapping[offset..offset + len], page_index)
+    }
+
+    //page page_index from the bytes it has in the run file
+    fn parse_page<'d>(&self, data: &'d [u8], page_index: usize) -> Result<Page<'d>> {
+        let offset = self.page_bounds(page_index).0;
+        Page::parse(data, offset).ok_or_else(|| {
+            Error::corruption(format!(
+                "bad page trailer at offset {} of run file {:?}",
+                offset, self.tmp_file
+            ))
+        })
+    }
+
+    //run f on page page_index, from the block cache if it holds the page. otherwise it is
+    //read from the mapping and only then copied into the cache, once f is done with it.
+    fn with_page<R>(&self, page_index: usize, f: impl FnOnce(&Page) -> Result<R>) -> Result<R> {
+        let cache = match &self.block_cache {
+            Some(cache) => cache,
+            None => {
+                let mapping = self.read_write_lock.read().unwrap();
+                return f(&self.page(&mapping, page_index)?);
             }
-            Err(_) => panic!("Open temp file failed!"),
         };
-        unsafe {
-            match MmapOptions::new()
-                .len(len)
-                .offset(offset as u64)
-                .map(self.mapping_file.as_ref().unwrap())
-            {
-                Ok(mmap) => {
-                    self.mapping = Some(mmap.make_mut().unwrap());
-                    let mut res: Vec<EntryT> = Vec::new();
-                    for i in 0..len / ENTRY_SIZE {
-                        let offset = ENTRY_SIZE * i as usize;
-                        res.push(EntryT {
-                            key: self.mapping.as_ref().unwrap().as_ref()[offset..offset + KEY_SIZE]
-                                .to_vec(),
-                            value: self.mapping.as_ref().unwrap().as_ref()
-                                [offset + KEY_SIZE..offset + KEY_SIZE + VALUE_SIZE]
-                                .to_vec(),
-                        })
-                    }
-                    res
-                }
-                Err(_) => panic!("Mapping failed!"),
-            }
+        if let Some(data) = cache.get(self.id, page_index) {
+            return f(&self.parse_page(&data, page_index)?);
+        }
+        let mapping = self.read_write_lock.read().unwrap();
+        let (offset, len) = self.page_bounds(page_index);
+        let data = &mapping.as_ref().expect("run is not sealed")[offset..offset + len];
+        let res = f(&self.parse_page(data, page_index)?)?;
+        cache.insert(self.id, page_index, Arc::from(data));
+        Ok(res)
+    }
+
+    fn bad_page(&self, page: &Page) -> Error {
+        Error::corruption(format!(
+            "bad entry offsets in page at offset {} of run file {:?}",
+            page.file_offset, self.tmp_file
+        ))
+    }
+
+    //decode the entries of page page_index from the mapping, bypassing the block cache
+    fn decode_page(&self, page_index: usize) -> Result<Vec<EntryT>> {
+        let mapping = self.read_write_lock.read().unwrap();
+        self.decode_from(&self.page(&mapping, page_index)?, 0)
+    }
+
+    //decode the entries of page from entry first on
+    fn decode_from(&self, page: &Page, first: usize) -> Result<Vec<EntryT>> {
+        if first == page.len() {
+            return Ok(Vec::new());
+        }
+        let start = page.offset(first);
+        if start > page.data.len() {
+            return Err(self.bad_page(page));
+        }
+        let res = self.decode_entries(&page.data[start..], page.file_offset + start)?;
+        if res.len() != page.len() - first {
+            return Err(self.bad_page(page));
+        }
+        Ok(res)
+    }
+
+    //map the entries of the sealed run in file for reading, for as long as the run lives
+    fn map_entries(&mut self, file: &File) -> Result<()> {
+        *self.read_write_lock.write().unwrap() = if self.bytes > 0 {
+            Some(unsafe { MmapOptions::new().len(self.bytes as usize).map(file)? })
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    //decode data read from offset of the run file, which must be whole entries
+    fn decode_entries(&self, data: &[u8], offset: usize) -> Result<Vec<EntryT>> {
+        let mut res: Vec<EntryT> = Vec::new();
+        let mut pos = 0;
+        while let Some((entry, used)) = EntryT::decode(&data[pos..]) {
+            res.push(entry);
+            pos += used;
+        }
+        //anything left over did not decode
+        if pos != data.len() {
+            return Err(Error::corruption(format!(
+                "bad entry at offset {} of run file {:?}",
+                offset + pos,
+                self.tmp_file
+            )));
         }
+        Ok(res)
     }
 
-    pub fn map_write(&mut self) {
+    pub fn num_pages(&self) -> usize {
+        self.page_offsets.len()
+    }
+
+    /// Decodes the entries of page `page_index` of a sealed run, reading the page from the
+    /// block cache if it holds it. Any number of readers can share the run.
+    pub fn read_page(&self, page_index: usize) -> Result<Vec<EntryT>> {
+        self.with_page(page_index, |page| self.decode_from(page, 0))
+    }
+
+    //the entries of page page_index from the first one with a key >= from on. only the
+    //entries from there on are decoded.
+    fn page_entries_from(&self, page_index: usize, from: &[u8]) -> Result<Vec<EntryT>> {
+        self.with_page(page_index, |page| {
+            let first = page.lower_bound(from).ok_or_else(|| self.bad_page(page))?;
+            self.decode_from(page, first)
+        })
+    }
+
+    //the run is written under this name and only renamed to tmp_file once it is complete,
+    //so a crash never leaves a half written file under the name of a run
+    pub fn building_file(&self) -> PathBuf {
+        self.tmp_file.with_extension("tmp")
+    }
+
+    //map a new run file that can hold capacity bytes of encoded entries
+    pub fn map_write(&mut self, capacity: u64) -> Result<()> {
         assert!(self.mapping.is_none());
+        //and the page trailers: an offset per entry and a count per page, and every page
+        //has an entry of at least ENTRY_HEADER_SIZE bytes
+        let max_entries = min(self.max_size, capacity / ENTRY_HEADER_SIZE as u64);
+        let capacity = capacity + 2 * TRAILER_SLOT_SIZE as u64 * max_entries;
 
-        match OpenOptions::new()
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .truncate(true)
-            .open(self.tmp_file.as_path())
-        {
-            Ok(file) => {
-                self.mapping_file = Some(file);
+            .open(self.building_file())?;
+
+        //mmap can not map an empty file
+        file.set_len(max(capacity, 1))?;
+
+        self.mapping = Some(unsafe { MmapMut::map_mut(&file)? });
+        self.mapping_file = Some(file);
+        Ok(())
+    }
+
+    /// Seals a run written through map_write: its file gets the metadata blocks and its
+    /// final name, and the entries are mapped for reading.
+    pub fn unmap(&mut self) -> Result<()> {
+        if self.mapping.is_some() {
+            self.finish_page();
+        }
+        if let Some(mapping) = self.mapping.take() {
+            mapping.flush()?;
+        }
+        //mapping_file is only kept by map_write. cut the spare capacity off the end of the file
+        //and put the metadata blocks there instead.
+        if let Some(mut file) = self.mapping_file.take() {
+            file.set_len(self.bytes)?;
+            self.write_metadata(&mut file)?;
+            //the whole file is on disk before it gets its final name, and the new name is on
+            //disk before anyone is told about the run
+            file.sync_all()?;
+            fs::rename(self.building_file(), &self.tmp_file)?;
+            if let Some(dir) = self.tmp_file.parent() {
+                File::open(dir)?.sync_all()?;
             }
-            Err(e) => panic!("Open temp file failed because {}!", e),
-        };
+            self.map_entries(&file)?;
+        }
+        Ok(())
+    }
 
-        let len = ENTRY_SIZE * self.max_size as usize;
+    //the bloom filter, then the prefix length and prefix filter if there is one. files
+    //without a prefix filter end right after the bloom filter.
+    fn encode_filter(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        encode_bloom(&mut res, &self.bloom_filter);
+        if let Some(prefix_filter) = &self.prefix_filter {
+            put_u32(&mut res, self.prefix_len as u32);
+            encode_bloom(&mut res, prefix_filter);
+        }
+        res
+    }
+
+    fn decode_filter(&mut self, data: &[u8]) -> Option<()> {
+        let mut decoder = Decoder::new(data);
+        self.bloom_filter = decode_bloom(&mut decoder)?;
+        if decoder.done() {
+            self.prefix_filter = None;
+            self.prefix_len = 0;
+            return Some(());
+        }
+        self.prefix_len = decoder.u32()? as usize;
+        self.prefix_filter = Some(decode_bloom(&mut decoder)?);
+        if self.prefix_len > 0 && decoder.done() {
+            Some(())
+        } else {
+            None
+        }
+    }
 
-        let mut fill: Vec<u8> = vec![32; len as usize];
-        fill.push(10);
-        self.mapping_file.as_ref().unwrap().write_all(fill.as_ref());
+    fn encode_index(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        put_u64(&mut res, self.size);
+        put_u64(&mut res, self.bytes);
+        put_bytes(&mut res, &self.max_key);
+        put_u32(&mut res, self.fence_pointers.len() as u32);
+        for (key, offset) in self.fence_pointers.iter().zip(self.page_offsets.iter()) {
+            put_u64(&mut res, *offset);
+            put_bytes(&mut res, key);
+        }
+        res
+    }
 
-        unsafe {
-            match Mmap::map(self.mapping_file.as_ref().unwrap()) {
-                Ok(mmap) => {
-                    self.mapping = Some(mmap.make_mut().unwrap());
-                }
-                Err(e) => panic!("Mapping failed because {}", e),
+    fn decode_index(&mut self, data: &[u8]) -> Option<()> {
+        let mut decoder = Decoder::new(data);
+        self.size = decoder.u64()?;
+        self.bytes = decoder.u64()?;
+        self.max_key = decoder.bytes()?.to_vec();
+        let pages = decoder.u32()?;
+        self.fence_pointers.clear();
+        self.page_offsets.clear();
+        for _ in 0..pages {
+            self.page_offsets.push(decoder.u64()?);
+            self.fence_pointers.push(decoder.bytes()?.to_vec());
+        }
+        //the pages have to cover the entries one after the other, so that every page can
+        //be sliced out of the mapping
+        let offsets = &self.page_offsets;
+        let pages_valid = match (offsets.first(), offsets.last()) {
+            (Some(first), Some(last)) => {
+                *first == 0
+                    && offsets.windows(2).all(|pair| pair[0] < pair[1])
+                    && *last < self.bytes
+                    && self.size > 0
             }
+            _ => self.bytes == 0 && self.size == 0,
+        };
+        if decoder.done() && pages_valid {
+            Some(())
+        } else {
+            None
         }
     }
 
-    pub fn unmap(&mut self) {
-        assert!(self.mapping.is_some());
+    //append the filter block, index block and footer after the entries
+    fn write_metadata(&mut self, file: &mut File) -> Result<()> {
+        let filter = self.encode_filter();
+        let index = self.encode_index();
+        let filter_offset = self.bytes;
+        let index_offset = filter_offset + filter.len() as u64;
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        put_u64(&mut footer, filter_offset);
+        put_u64(&mut footer, filter.len() as u64);
+        put_u64(&mut footer, index_offset);
+        put_u64(&mut footer, index.len() as u64);
+        put_u32(&mut footer, crc32(&filter));
+        put_u32(&mut footer, crc32(&index));
+        put_u64(&mut footer, RUN_MAGIC);
 
-        self.mapping = None;
-        self.mapping_file = None;
+        file.seek(SeekFrom::Start(filter_offset))?;
+        file.write_all(&filter)?;
+        file.write_all(&index)?;
+        file.write_all(&footer)?;
+        self.file_size = index_offset + index.len() as u64 + FOOTER_SIZE as u64;
+        Ok(())
     }
 
-    pub fn get(&mut self, key: &KeyT) -> Option<ValueT> {
+    /// Restores the bloom filter, fence pointers and sizes of a sealed run from the
+    /// metadata blocks at the end of its file, without reading any entry.
+    pub fn load_metadata(&mut self) -> Result<()> {
+        let mut file = File::open(&self.tmp_file)?;
+        let file_size = file.metadata()?.len();
+        if file_size < FOOTER_SIZE as u64 {
+            return Err(Error::corruption("run file is too short for a footer"));
+        }
+        let mut footer = vec![0; FOOTER_SIZE];
+        file.seek(SeekFrom::Start(file_size - FOOTER_SIZE as u64))?;
+        file.read_exact(&mut footer)?;
+        let mut decoder = Decoder::new(&footer);
+        let (filter_offset, filter_len) = (decoder.u64().unwrap(), decoder.u64().unwrap());
+        let (index_offset, index_len) = (decoder.u64().unwrap(), decoder.u64().unwrap());
+        let (filter_crc, index_crc) = (decoder.u32().unwrap(), decoder.u32().unwrap());
+        let index_end = index_offset
+            .checked_add(index_len)
+            .and_then(|end| end.checked_add(FOOTER_SIZE as u64));
+        if decoder.u64().unwrap() != RUN_MAGIC
+            || filter_offset.checked_add(filter_len) != Some(index_offset)
+            || index_end != Some(file_size)
+        {
+            return Err(Error::corruption("bad run file footer"));
+        }
+
+        let mut blocks = vec![0; (filter_len + index_len) as usize];
+        file.seek(SeekFrom::Start(filter_offset))?;
+        file.read_exact(&mut blocks)?;
+        let (filter, index) = blocks.split_at(filter_len as usize);
+        if crc32(filter) != filter_crc || crc32(index) != index_crc {
+            return Err(Error::corruption("run metadata checksum mismatch"));
+        }
+        if self.decode_filter(filter).is_none() {
+            return Err(Error::corruption("bad run filter block"));
+        }
+        if self.decode_index(index).is_none() || self.bytes != filter_offset {
+            return Err(Error::corruption("bad run index block"));
+        }
+        self.file_size = file_size;
+        self.map_entries(&file)
+    }
+
+    //return the entry of key in this run, which may be a tombstone
+    pub fn get(&self, key: &KeyT) -> Result<Option<EntryT>> {
         //bloom_filter
-        let read_lock = self.read_write_lock.read().unwrap().clone();
         if self.bloom_filter.check(key) {
             //it is very likely that this Run contains target entry. False positives may occur.
-            if *key < self.fence_pointers[0] || *key > self.max_key {
-                return None;
+            if self.size == 0 || *key < self.fence_pointers[0] || *key > self.max_key {
+                return Ok(None);
             }
-            let page_index: usize;
 
-            match self.fence_pointers.binary_search(key) {
-                Ok(find) => {
-                    page_index = find;
-                }
-                Err(not) => {
-                    page_index = not - 1;
+            let page_index = match self.fence_pointers.binary_search(key) {
+                Ok(find) => find,
+                Err(not) => not - 1,
+            };
+
+            //search the page in place and only decode the entry found
+            self.with_page(page_index, |page| {
+                let found = page.lower_bound(key).ok_or_else(|| self.bad_page(page))?;
+                if found == page.len() || page.key(found) != Some(key.as_slice()) {
+                    return Ok(None);
                 }
-            }
-            assert!(page_index >= 0);
-
-            self.map_read(page_size::get(), page_index * page_size::get());
-
-            let mut val: ValueT = vec![];
-            for i in 0..page_size::get() / ENTRY_SIZE {
-                let offset = ENTRY_SIZE * i as usize;
-                if self.mapping.as_ref().unwrap().as_ref()[offset..offset + KEY_SIZE].to_vec()
-                    == *key
-                {
-                    val = self.mapping.as_ref().unwrap().as_ref()
-                        [offset + KEY_SIZE..offset + KEY_SIZE + VALUE_SIZE]
-                        .to_vec();
+                match page.entry(found) {
+                    Some(entry) => Ok(Some(entry)),
+                    None => Err(self.bad_page(page)),
                 }
-            }
-
-            self.unmap();
-
-            Some(val)
+            })
         } else {
             //not in this run according to bloom filter
             //println!("not in this Run according to bloom filter");
-            None
+            Ok(None)
         }
     }
 
-    pub fn get_keys(&mut self) -> Vec<KeyT> {
-        self.map_read_default();
-        let mut res: Vec<KeyT> = Vec::new();
-        for i in 0..self.max_size {
-            let offset = ENTRY_SIZE * i as usize;
-            let key = self.mapping.as_ref().unwrap().as_ref()[offset..offset + KEY_SIZE].to_vec();
-            if key[KEY_SIZE - 1] == 32 {
-                break;
+    /// Whether this run may hold a key starting with `prefix`. False positives may occur.
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        if self.size == 0 || self.max_key.as_slice() < prefix {
+            return false;
+        }
+        //the first key is above every key with the prefix
+        let first = &self.fence_pointers[0];
+        if first.as_slice() > prefix && !first.starts_with(prefix) {
+            return false;
+        }
+        match &self.prefix_filter {
+            Some(prefix_filter) if prefix.len() >= self.prefix_len => {
+                prefix_filter.check(&prefix[..self.prefix_len])
             }
-            res.push(key);
+            _ => true,
         }
-        res
     }
 
-    pub fn range(&mut self, start: &KeyT, end: &KeyT) -> Vec<EntryT> {
-        let read_lock = self.read_write_lock.read().unwrap().clone();
-        let mut res: Vec<EntryT> = Vec::new();
+    pub fn get_keys(&self) -> Result<Vec<KeyT>> {
+        Ok(self
+            .map_read_default()?
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect())
+    }
 
-        let page_start: usize;
-        let page_end: usize;
+    pub fn range(&self, start: &KeyT, end: &KeyT) -> Result<Vec<EntryT>> {
+        let mut res: Vec<EntryT> = Vec::new();
 
-        if *start > self.max_key || self.fence_pointers[0] > *end {
-            return res;
+        if self.size == 0 || start > end || *start > self.max_key || self.fence_pointers[0] > *end {
+            return Ok(res);
         }
 
-        if *start < self.fence_pointers[0] {
-            page_start = 0;
+        let page_start = if *start < self.fence_pointers[0] {
+            0
         } else {
             match self.fence_pointers.binary_search(start) {
-                Ok(find) => {
-                    page_start = find;
-                }
-                Err(not) => {
-                    page_start = not - 1;
-                }
+                Ok(find) => find,
+                Err(not) => not - 1,
             }
-        }
+        };
 
-        if *end > self.max_key {
-            page_end = 0;
-        } else {
-            match self.fence_pointers.binary_search(end) {
-                Ok(find) => {
-                    page_end = find;
-                }
-                Err(not) => {
-                    page_end = not - 1;
+        //the last page holding keys <= end, which is the last page if end > max_key
+        let page_end = match self.fence_pointers.binary_search(end) {
+            Ok(find) => find,
+            Err(not) => not - 1,
+        };
+
+        for page_index in page_start..=page_end {
+            for entry in self.page_entries_from(page_index, start)? {
+                if entry.key > *end {
+                    break;
                 }
+                res.push(entry);
             }
         }
 
-        assert!(page_start < page_end);
-        let num_pages = page_end - page_start;
-        self.map_read(num_pages * page_size::get(), page_start * page_size::get());
-
-        let num_entries = num_pages * page_size::get() / ENTRY_SIZE;
-        res.reserve(num_entries);
+        Ok(res)
+    }
 
-        for i in 0..num_entries {
-            let offset = ENTRY_SIZE * i as usize;
-            let entry = EntryT {
-                key: self.mapping.as_ref().unwrap().as_ref()[offset..offset + KEY_SIZE].to_vec(),
-                value: self.mapping.as_ref().unwrap().as_ref()
-                    [offset + KEY_SIZE..offset + KEY_SIZE + VALUE_SIZE]
-                    .to_vec(),
-            };
-            if *start <= entry.key && entry.key <= *end {
-                res.push(entry);
-            }
+    //append the trailer of the page being written, if there is one
+    fn finish_page(&mut self) {
+        if self.page_offsets.is_empty() {
+            return;
+        }
+        let count = self.page_entry_offsets.len() as u32;
+        let mut trailer = Vec::with_capacity((count as usize + 1) * TRAILER_SLOT_SIZE);
+        for entry_offset in self.page_entry_offsets.drain(..) {
+            put_u32(&mut trailer, entry_offset);
         }
+        put_u32(&mut trailer, count);
+        let offset = self.bytes as usize;
+        let mapping = self.mapping.as_mut().unwrap();
+        assert!(offset + trailer.len() <= mapping.len());
+        mapping[offset..offset + trailer.len()].copy_from_slice(&trailer);
+        self.bytes += trailer.len() as u64;
+    }
 
-        self.unmap();
+    //update the bloom filter, fence pointers and sizes for an entry appended at offset self.bytes
+    fn track(&mut self, entry: &EntryT) {
+        self.page_entry_offsets
+            .push((self.bytes - self.page_offsets.last().unwrap()) as u32);
+        self.max_key = max(entry.key.clone(), self.max_key.clone());
 
-        res
+        //set true for this key in this Run. For later more efficient search and avoid unnecessary file I/O operations.
+        self.bloom_filter.set(&entry.key);
+        if let Some(prefix_filter) = self.prefix_filter.as_mut() {
+            if entry.key.len() >= self.prefix_len {
+                prefix_filter.set(&entry.key[..self.prefix_len]);
+            }
+        }
+        self.size += 1;
+        self.bytes += entry.encoded_len() as u64;
     }
 
     pub fn put(&mut self, entry: &EntryT) {
         assert!(self.size < self.max_size);
-        let mut write_lock = self.read_write_lock.write().unwrap();
-        if self.size % (page_size::get() / ENTRY_SIZE) as u64 == 0 {
+
+        //a new page starts with the first entry at least one page_size after the start of the last page
+        let page_full = match self.page_offsets.last() {
+            Some(page_offset) => self.bytes >= page_offset + page_size::get() as u64,
+            None => true,
+        };
+        if page_full {
+            self.finish_page();
             self.fence_pointers.push(entry.key.clone());
+            self.page_offsets.push(self.bytes);
         }
 
-        self.max_key = max(entry.key.clone(), self.max_key.clone());
-
-        let mut entry_data: Vec<u8> = Vec::new();
-        entry_data.extend(entry.key.iter());
-        entry_data.extend(entry.value.iter());
+        let offset = self.bytes as usize;
+        let mapping = self.mapping.as_mut().unwrap();
+        assert!(offset + entry.encoded_len() <= mapping.len());
+        entry.encode_to(&mut mapping[offset..]);
 
-        let mut offset = ENTRY_SIZE * self.size as usize;
-        for byte in entry_data {
-            self.mapping.as_mut().unwrap().as_mut()[offset] = byte;
-            offset += 1;
-        }
+        self.track(entry);
+    }
+}
 
-        //set true for this key in this Run. For later more efficient search and avoid unnecessary file I/O operations.
-        self.bloom_filter.set(&entry.key);
-        self.size += 1;
+fn encode_bloom<T: ?Sized>(res: &mut Vec<u8>, bloom: &bloomfilter::Bloom<T>) {
+    put_u64(res, bloom.number_of_bits());
+    put_u32(res, bloom.number_of_hash_functions());
+    for (k0, k1) in bloom.sip_keys().iter() {
+        put_u64(res, *k0);
+        put_u64(res, *k1);
     }
+    put_bytes(res, &bloom.bitmap());
+}
 
-    // fn file_size(&self) -> u64 {
-    //     self.max_size * size_of::<EntryT>() as u64
-    // }
+fn decode_bloom<T: ?Sized>(decoder: &mut Decoder) -> Option<bloomfilter::Bloom<T>> {
+    let bits = decoder.u64()?;
+    let hashes = decoder.u32()?;
+    let sip_keys = [
+        (decoder.u64()?, decoder.u64()?),
+        (decoder.u64()?, decoder.u64()?),
+    ];
+    let bitmap = decoder.bytes()?;
+    Some(bloomfilter::Bloom::from_existing(
+        bitmap, bits, hashes, sip_keys,
+    ))
+}
+
+//entries are equal if their keys are, tests compare everything a run stores
+#[cfg(test)]
+fn stored(entries: &[EntryT]) -> Vec<(KeyT, ValueT, EntryKind)> {
+    entries
+        .iter()
+        .map(|entry| (entry.key.clone(), entry.value.clone(), entry.kind))
+        .collect()
 }
 
 #[test]
 fn test_run() {
     use crate::run;
-    use std::fs;
-    fs::create_dir("/tmp/unit_test");
-    fs::create_dir("/tmp/unit_test/0");
-    let mut run = run::Run::new(10, 0.5, "unit_test", 0, 0);
-    let entry1 = EntryT {
-        key: vec![97; KEY_SIZE],
-        value: vec![33; VALUE_SIZE],
-    };
-    let entry2 = EntryT {
-        key: vec![98; KEY_SIZE],
-        value: vec![33; VALUE_SIZE],
-    };
-    run.map_write();
+    let _ = fs::create_dir_all("/tmp/unit_test/0");
+    let mut run = run::Run::new(10, 0.5, 0, Path::new("/tmp/unit_test"), 0, 0);
+    let entry1 = EntryT::new(vec![97; 8], vec![33; 24]);
+    let entry2 = EntryT::new(vec![98; 8], vec![33; 24]);
+    run.map_write((entry1.encoded_len() + entry2.encoded_len()) as u64)
+        .unwrap();
     run.put(&entry1);
     run.put(&entry2);
-    run.unmap();
-    let key1: Vec<u8> = vec![97; KEY_SIZE];
-    let key2: Vec<u8> = vec![98; KEY_SIZE];
-    println!("{}", std::str::from_utf8(&run.get(&key1).unwrap()).unwrap());
-    println!("{}", std::str::from_utf8(&run.get(&key2).unwrap()).unwrap());
-    println!("{:?}", run.get_keys());
+    run.unmap().unwrap();
+    let key1: Vec<u8> = vec![97; 8];
+    let key2: Vec<u8> = vec![98; 8];
+    assert_eq!(vec![33; 24], run.get(&key1).unwrap().unwrap().value);
+    assert_eq!(vec![33; 24], run.get(&key2).unwrap().unwrap().value);
+    assert_eq!(vec![key1, key2], run.get_keys().unwrap());
+}
+
+#[test]
+fn test_building_file() {
+    let _ = fs::create_dir_all("/tmp/unit_test_building/0");
+    let entry = EntryT::new(b"key".to_vec(), b"value".to_vec());
+    let mut run = Run::new(10, 10.0, 0, Path::new("/tmp/unit_test_building"), 0, 0);
+    let _ = fs::remove_file(&run.tmp_file);
+    run.map_write(entry.encoded_len() as u64).unwrap();
+    run.put(&entry);
+    //a crash now leaves only the building file behind
+    assert!(run.building_file().exists());
+    assert!(!run.tmp_file.exists());
+    run.unmap().unwrap();
+    assert!(!run.building_file().exists());
+    assert_eq!(run.file_size, fs::metadata(&run.tmp_file).unwrap().len());
+    assert_eq!(
+        Some(b"value".to_vec()),
+        run.get(&entry.key).unwrap().map(|e| e.value)
+    );
+}
+
+#[test]
+fn test_variable_length() {
+    let _ = fs::create_dir_all("/tmp/unit_test_var/0");
+    let count = 500;
+    let entries: Vec<EntryT> = (0..count)
+        .map(|i| {
+            EntryT::new(
+                format!("key-{:05}-{}", i, "k".repeat(i % 37)).into_bytes(),
+                format!("{{\"id\": {}, \"pad\": \"{}\"}}", i, "v".repeat(i % 301)).into_bytes(),
+            )
+        })
+        .collect();
+    let capacity: usize = entries.iter().map(|e| e.encoded_len()).sum();
+    let mut run = Run::new(count as u64, 10.0, 0, Path::new("/tmp/unit_test_var"), 0, 0);
+    run.map_write(capacity as u64).unwrap();
+    for entry in entries.iter() {
+        run.put(entry);
+    }
+    run.unmap().unwrap();
+    assert!(run.fence_pointers.len() > 1);
+    for entry in entries.iter() {
+        assert_eq!(
+            Some(entry.value.clone()),
+            run.get(&entry.key).unwrap().map(|e| e.value)
+        );
+    }
+    assert_eq!(None, run.get(&b"key-00000-extra".to_vec()).unwrap());
+
+    let mut reloaded = Run::from(count as u64, 10.0, 0, 0, 0, run.tmp_file.clone());
+    reloaded.load_metadata().unwrap();
+    assert_eq!(run.size, reloaded.size);
+    assert_eq!(run.bytes, reloaded.bytes);
+    assert_eq!(run.file_size, reloaded.file_size);
+    assert_eq!(run.max_key, reloaded.max_key);
+    assert_eq!(run.fence_pointers, reloaded.fence_pointers);
+    assert_eq!(run.page_offsets, reloaded.page_offsets);
+    assert_eq!(run.bloom_filter.bitmap(), reloaded.bloom_filter.bitmap());
+    for entry in entries.iter() {
+        assert_eq!(
+            Some(entry.value.clone()),
+            reloaded.get(&entry.key).unwrap().map(|e| e.value)
+        );
+    }
+}
+
+#[test]
+fn test_page_search() {
+    let _ = fs::create_dir_all("/tmp/unit_test_search/0");
+    let count = 600;
+    //every other key, with lengths that vary inside a page
+    let key = |i: usize| format!("{:05}{}", i, "k".repeat(i % 13)).into_bytes();
+    let entries: Vec<EntryT> = (0..count)
+        .map(|i| match i % 9 {
+            0 => EntryT::tombstone(key(i * 2)),
+            _ => EntryT::new(key(i * 2), vec![b'v'; i % 70]),
+        })
+        .collect();
+    let mut run = Run::new(
+        count as u64,
+        10.0,
+        0,
+        Path::new("/tmp/unit_test_search"),
+        0,
+        0,
+    );
+    run.map_write(entries.iter().map(|e| e.encoded_len() as u64).sum())
+        .unwrap();
+    for entry in entries.iter() {
+        run.put(entry);
+    }
+    run.unmap().unwrap();
+    assert!(run.num_pages() > 2);
+    assert_eq!(stored(&entries), stored(&run.map_read_default().unwrap()));
+
+    let mut cached = Run::from(count as u64, 10.0, 0, 0, 0, run.tmp_file.clone());
+    cached.load_metadata().unwrap();
+    cached.block_cache = Some(Arc::new(BlockCache::new(1 << 20)));
+    for run in [&run, &cached].iter() {
+        for (i, entry) in entries.iter().enumerate() {
+            let found = run.get(&key(i * 2)).unwrap();
+            assert_eq!(
+                stored(std::slice::from_ref(entry)),
+                stored(found.as_slice())
+            );
+            //between two keys of the run
+            assert_eq!(None, run.get(&key(i * 2 + 1)).unwrap());
+        }
+        //the range starts between two keys in the middle of a page
+        assert_eq!(
+            stored(&entries[101..=250]),
+            stored(&run.range(&key(201), &key(500)).unwrap())
+        );
+    }
+    //every page was read, and the cache holds them as they are in the run file
+    let cache = cached.block_cache.as_ref().unwrap();
+    assert!(cache.hits() > 0);
+    assert_eq!(cached.bytes as usize, cache.usage());
+}
+
+#[test]
+fn test_read_mapping() {
+    let _ = fs::create_dir_all("/tmp/unit_test_mapping/0");
+    let entries: Vec<EntryT> = (0..100u32)
+        .map(|i| EntryT::new(format!("key{:03}", i).into_bytes(), vec![i as u8; 100]))
+        .collect();
+    let mut run = Run::new(100, 10.0, 0, Path::new("/tmp/unit_test_mapping"), 0, 0);
+    run.map_write(100 * 120).unwrap();
+    assert!(run.read_write_lock.read().unwrap().is_none());
+    for entry in entries.iter() {
+        run.put(entry);
+    }
+    run.unmap().unwrap();
+    assert_eq!(
+        run.bytes as usize,
+        run.read_write_lock.read().unwrap().as_ref().unwrap().len()
+    );
+
+    let mut reloaded = Run::from(100, 10.0, 0, 0, 0, run.tmp_file.clone());
+    reloaded.load_metadata().unwrap();
+    assert!(reloaded.read_write_lock.read().unwrap().is_some());
+    //lookups read the mapping, not the file
+    fs::remove_file(&run.tmp_file).unwrap();
+    for run in [&run, &reloaded].iter() {
+        assert_eq!(stored(&entries), stored(&run.map_read_default().unwrap()));
+        assert_eq!(
+            stored(&entries[42..43]),
+            stored(run.get(&entries[42].key).unwrap().as_slice())
+        );
+    }
+
+    //an empty run has nothing to map
+    let mut empty = Run::new(10, 10.0, 0, Path::new("/tmp/unit_test_mapping"), 0, 1);
+    empty.map_write(0).unwrap();
+    empty.unmap().unwrap();
+    assert!(empty.read_write_lock.read().unwrap().is_none());
+    assert!(empty.map_read_default().unwrap().is_empty());
+}
+
+#[test]
+fn test_prefix_filter() {
+    let _ = fs::create_dir_all("/tmp/unit_test_prefix/0");
+    //only even users
+    let mut keys: Vec<KeyT> = (0..400)
+        .map(|i| format!("user/{:03}/{}", (i % 20) * 2, i).into_bytes())
+        .collect();
+    keys.sort();
+    let mut run = Run::new(400, 10.0, 8, Path::new("/tmp/unit_test_prefix"), 0, 0);
+    run.map_write(400 * 64).unwrap();
+    for key in keys.iter() {
+        run.put(&EntryT::new(key.clone(), b"v".to_vec()));
+    }
+    run.unmap().unwrap();
+
+    let mut reloaded = Run::from(400, 10.0, 0, 0, 0, run.tmp_file.clone());
+    reloaded.load_metadata().unwrap();
+    assert_eq!(8, reloaded.prefix_len);
+    for run in [&run, &reloaded].iter() {
+        assert!(run.may_contain_prefix(b"user/010"));
+        assert!(run.may_contain_prefix(b"user/010/"));
+        assert!(!run.may_contain_prefix(b"user/011"));
+        assert!(!run.may_contain_prefix(b"user/011/3"));
+        //shorter than the filtered prefixes, only the key range is checked
+        assert!(run.may_contain_prefix(b"user/"));
+        assert!(!run.may_contain_prefix(b"admin/"));
+        assert!(!run.may_contain_prefix(b"zoo"));
+    }
+
+    //runs without a prefix filter reload without one
+    let mut plain = Run::new(10, 10.0, 0, Path::new("/tmp/unit_test_prefix"), 0, 1);
+    plain.map_write(64).unwrap();
+    plain.put(&EntryT::new(b"user/011/1".to_vec(), b"v".to_vec()));
+    plain.unmap().unwrap();
+    let mut reloaded = Run::from(10, 10.0, 8, 0, 1, plain.tmp_file.clone());
+    reloaded.load_metadata().unwrap();
+    assert!(reloaded.prefix_filter.is_none());
+    assert!(reloaded.may_contain_prefix(b"user/011"));
+}
+
+#[test]
+fn test_range() {
+    let _ = fs::create_dir_all("/tmp/unit_test_range/0");
+    let count = 400;
+    let key = |i: usize| format!("key-{:05}", i).into_bytes();
+    let mut run = Run::new(
+        count as u64,
+        10.0,
+        0,
+        Path::new("/tmp/unit_test_range"),
+        0,
+        0,
+    );
+    let entries: Vec<EntryT> = (0..count)
+        .map(|i| EntryT::new(key(i * 2), vec![b'v'; 50]))
+        .collect();
+    run.map_write(entries.iter().map(|e| e.encoded_len() as u64).sum())
+        .unwrap();
+    for entry in entries.iter() {
+        run.put(entry);
+    }
+    run.unmap().unwrap();
+    assert!(run.num_pages() > 2);
+
+    let keys = |res: Vec<EntryT>| res.into_iter().map(|e| e.key).collect::<Vec<KeyT>>();
+    //within a page, across pages, past the last key and before the first one
+    assert_eq!(
+        vec![key(10), key(12)],
+        keys(run.range(&key(9), &key(13)).unwrap())
+    );
+    let expected: Vec<KeyT> = (50..300).map(|i| key(i * 2)).collect();
+    assert_eq!(expected, keys(run.range(&key(100), &key(599)).unwrap()));
+    let expected: Vec<KeyT> = (390..count).map(|i| key(i * 2)).collect();
+    assert_eq!(expected, keys(run.range(&key(779), &key(99999)).unwrap()));
+    assert_eq!(count, run.range(&Vec::new(), &key(99999)).unwrap().len());
+    assert!(run.range(&key(99999), &key(999999)).unwrap().is_empty());
+    assert!(run.range(&key(13), &key(9)).unwrap().is_empty());
+}
+
+#[test]
+fn test_corrupted_metadata() {
+    let _ = fs::create_dir_all("/tmp/unit_test_meta/0");
+    let entry = EntryT::new(b"key".to_vec(), b"value".to_vec());
+    let mut run = Run::new(10, 10.0, 0, Path::new("/tmp/unit_test_meta"), 0, 0);
+    run.map_write(entry.encoded_len() as u64).unwrap();
+    run.put(&entry);
+    run.unmap().unwrap();
+    assert_eq!(run.file_size, fs::metadata(&run.tmp_file).unwrap().len());
+
+    let mut data = fs::read(&run.tmp_file).unwrap();
+    //an entry kind that does not exist
+    data[0] = 7;
+    fs::write(&run.tmp_file, &data).unwrap();
+    assert!(matches!(run.get(&entry.key), Err(Error::Corruption(_))));
+    //a page trailer claiming more entries than the page holds
+    data[0] = 0;
+    data[run.bytes as usize - 1] = 0xff;
+    fs::write(&run.tmp_file, &data).unwrap();
+    assert!(matches!(run.get(&entry.key), Err(Error::Corruption(_))));
+    assert!(matches!(run.map_read_default(), Err(Error::Corruption(_))));
+    data[run.bytes as usize - 1] = 0;
+
+    //flip a bit of the index block
+    let index_byte = data.len() - FOOTER_SIZE - 1;
+    data[index_byte] ^= 1;
+    fs::write(&run.tmp_file, &data).unwrap();
+    let mut reloaded = Run::from(10, 10.0, 0, 0, 0, run.tmp_file.clone());
+    assert!(matches!(
+        reloaded.load_metadata(),
+        Err(Error::Corruption(_))
+    ));
+    data[index_byte] ^= 1;
+
+    //footer lengths that overflow the offsets they are added to
+    let footer = data.len() - FOOTER_SIZE;
+    for len_start in [footer + 8, footer + 24].iter() {
+        let mut bad = data.clone();
+        bad[*len_start..*len_start + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&run.tmp_file, &bad).unwrap();
+        assert!(matches!(
+            reloaded.load_metadata(),
+            Err(Error::Corruption(_))
+        ));
+    }
+
+    //index blocks with a good checksum whose page offsets do not fit the entries
+    let index_offset = u64::from_le_bytes(data[footer + 16..footer + 24].try_into().unwrap());
+    let fence = run.fence_pointers[0].clone();
+    let bad_pages = [
+        (vec![run.bytes], vec![fence.clone()]),
+        (vec![1], vec![fence.clone()]),
+        (vec![0, 0], vec![fence.clone(), fence.clone()]),
+        (vec![], vec![]),
+    ];
+    for (offsets, fences) in bad_pages.iter() {
+        let mut bad_run = Run::from(10, 10.0, 0, 0, 0, run.tmp_file.clone());
+        bad_run.size = run.size;
+        bad_run.bytes = run.bytes;
+        bad_run.max_key = run.max_key.clone();
+        bad_run.page_offsets = offsets.clone();
+        bad_run.fence_pointers = fences.clone();
+        let index = bad_run.encode_index();
+        let mut bad = data[..index_offset as usize].to_vec();
+        bad.extend_from_slice(&index);
+        bad.extend_from_slice(&data[footer..footer + 24]);
+        put_u64(&mut bad, index.len() as u64);
+        bad.extend_from_slice(&data[footer + 32..footer + 36]);
+        put_u32(&mut bad, crc32(&index));
+        put_u64(&mut bad, RUN_MAGIC);
+        fs::write(&run.tmp_file, &bad).unwrap();
+        assert!(matches!(
+            reloaded.load_metadata(),
+            Err(Error::Corruption(_))
+        ));
+    }
+
+    fs::write(&run.tmp_file, b"short").unwrap();
+    assert!(reloaded.load_metadata().is_err());
 }
 
 #[test]
 fn test_multithreading() {
-    println!("test multi threading!");
+    use std::thread;
+    let _ = fs::create_dir_all("/tmp/unit_test_threads/0");
+    let entries: Vec<EntryT> = (0..1000u32)
+        .map(|i| {
+            let key = format!("key{:04}", i).into_bytes();
+            match i % 7 {
+                0 => EntryT::tombstone(key),
+                _ => EntryT::new(key, i.to_string().into_bytes()),
+            }
+        })
+        .collect();
+    let mut run = Run::new(1000, 10.0, 0, Path::new("/tmp/unit_test_threads"), 0, 0);
+    run.block_cache = Some(Arc::new(BlockCache::new(16 << 10)));
+    run.map_write(entries.iter().map(EntryT::encoded_len).sum::<usize>() as u64)
+        .unwrap();
+    for entry in entries.iter() {
+        run.put(entry);
+    }
+    run.unmap().unwrap();
+    //a sealed run is only read, so any number of threads can share it
+    let run = Arc::new(run);
+    let entries = Arc::new(entries);
+    let readers: Vec<_> = (0..4)
+        .map(|t| {
+            let run = run.clone();
+            let entries = entries.clone();
+            thread::spawn(move || {
+                for entry in entries.iter().skip(t).step_by(4) {
+                    let found = run.get(&entry.key).unwrap();
+                    assert_eq!(
+                        stored(std::slice::from_ref(entry)),
+                        stored(found.as_slice())
+                    );
+                }
+                assert_eq!(
+                    stored(&entries[100..=200]),
+                    stored(&run.range(&entries[100].key, &entries[200].key).unwrap())
+                );
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap();
+    }
 }
@@ -0,0 +1,358 @@
+//a lock-free skiplist memtable. nodes are only ever added, never unlinked, until the whole
+//list is dropped after its flush. so a reader never meets a freed node, and an insert only
+//has to compare and swap the links in front of its node, which lets writers insert in
+//parallel with each other and with readers.
+//every write gets its own node, ordered by key and then newest first. a lookup stops at
+//the first node of its key, and a scan skips the older nodes behind it.
+use crate::data_type::{EntryT, KeyT};
+use crate::memtable::MemTable;
+use rand::{thread_rng, Rng};
+use std::cmp::Ordering as CmpOrdering;
+use std::mem::size_of;
+use std::ops::Bound;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+const MAX_HEIGHT: usize = 12;
+//a node reaches the next level with a chance of 1 in BRANCHING
+const BRANCHING: u32 = 4;
+
+struct Node {
+    entry: EntryT,
+    seq: u64,
+    //the next node on every level the node is on
+    next: Box<[AtomicPtr<Node>]>,
+}
+
+impl Node {
+    //whether the node goes before the position of key written with seq
+    fn precedes(&self, key: &[u8], seq: u64) -> bool {
+        match self.entry.key.as_slice().cmp(key) {
+            CmpOrdering::Less => true,
+            CmpOrdering::Equal => self.seq > seq,
+            CmpOrdering::Greater => false,
+        }
+    }
+}
+
+/// The lock-free skiplist memtable
+pub struct SkipList {
+    //the links of the head, one per level
+    head: Box<[AtomicPtr<Node>]>,
+    //levels used so far
+    height: AtomicUsize,
+    len: AtomicUsize,
+    usage: AtomicUsize,
+    sequence: AtomicU64,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        SkipList::new()
+    }
+}
+
+impl SkipList {
+    pub fn new() -> SkipList {
+        SkipList {
+            head: new_links(MAX_HEIGHT),
+            height: AtomicUsize::new(1),
+            len: AtomicUsize::new(0),
+            usage: AtomicUsize::new(0),
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    //the links to follow from pred on level to reach the position of key and seq, and the
+    //node after that position
+    fn find_on_level<'a>(
+        &'a self,
+        key: &[u8],
+        seq: u64,
+        level: usize,
+        mut pred: &'a [AtomicPtr<Node>],
+    ) -> (&'a [AtomicPtr<Node>], *mut Node) {
+        loop {
+            let next = pred[level].load(Ordering::Acquire);
+            //nodes live as long as the list
+            match unsafe { next.as_ref() } {
+                Some(node) if node.precedes(key, seq) => pred = &node.next,
+                _ => return (pred, next),
+            }
+        }
+    }
+
+    //the first node at or after the position of key and seq
+    fn seek(&self, key: &[u8], seq: u64) -> *mut Node {
+        let mut pred = &self.head[..];
+        for level in (0..self.height.load(Ordering::Acquire)).rev() {
+            pred = self.find_on_level(key, seq, level, pred).0;
+        }
+        pred[0].load(Ordering::Acquire)
+    }
+
+    //the last node with a key below key, or the last node of all without a key
+    fn last_below(&self, key: Option<&[u8]>) -> Option<&Node> {
+        let mut pred = &self.head[..];
+        let mut last = None;
+        for level in (0..self.height.load(Ordering::Acquire)).rev() {
+            loop {
+                match unsafe { pred[level].load(Ordering::Acquire).as_ref() } {
+                    Some(node) if key.is_none_or(|key| node.entry.key.as_slice() < key) => {
+                        pred = &node.next;
+                        last = Some(node);
+                    }
+                    _ => break,
+                }
+            }
+        }
+        last
+    }
+
+    //the newest entry of key written before seq
+    fn get_at(&self, key: &[u8], seq: u64) -> Option<EntryT> {
+        let mut node = unsafe { self.seek(key, u64::MAX).as_ref() };
+        while let Some(found) = node {
+            if found.entry.key != key {
+                return None;
+            }
+            if found.seq < seq {
+                return Some(found.entry.clone());
+            }
+            node = unsafe { found.next[0].load(Ordering::Acquire).as_ref() };
+        }
+        None
+    }
+}
+
+fn new_links(height: usize) -> Box<[AtomicPtr<Node>]> {
+    (0..height)
+        .map(|_| AtomicPtr::new(ptr::null_mut()))
+        .collect()
+}
+
+fn random_height() -> usize {
+    let mut rng = thread_rng();
+    let mut height = 1;
+    while height < MAX_HEIGHT && rng.gen_range(0, BRANCHING) == 0 {
+        height += 1;
+    }
+    height
+}
+
+impl MemTable for SkipList {
+    fn get(&self, key: &KeyT) -> Option<EntryT> {
+        //the newest node of key comes first
+        match unsafe { self.seek(key, u64::MAX).as_ref() } {
+            Some(node) if node.entry.key == *key => Some(node.entry.clone()),
+            _ => None,
+        }
+    }
+
+    fn put(&self, entry: EntryT, seq: u64) {
+        let height = random_height();
+        let usage = size_of::<Node>()
+            + height * size_of::<AtomicPtr<Node>>()
+            + entry.key.len()
+            + entry.value.len();
+        let node = Box::into_raw(Box::new(Node {
+            entry,
+            seq,
+            next: new_links(height),
+        }));
+        //the node is only freed with the list
+        let node_ref = unsafe { &*node };
+        let key = node_ref.entry.key.as_slice();
+        let list_height = self.height.fetch_max(height, Ordering::AcqRel).max(height);
+
+        //the links in front of the position of the node, and the nodes after it, per level
+        let mut preds = [&self.head[..]; MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+        let mut pred = &self.head[..];
+        for level in (0..list_height).rev() {
+            let (found_pred, found_succ) = self.find_on_level(key, seq, level, pred);
+            preds[level] = found_pred;
+            succs[level] = found_succ;
+            pred = found_pred;
+        }
+        //link the node bottom up. once it is on level 0 it is in the list, the levels
+        //above only make it faster to find. a failed swap means another node went in
+        //right there, so the position is searched again from the same predecessor.
+        for level in 0..height {
+            loop {
+                node_ref.next[level].store(succs[level], Ordering::Relaxed);
+                match preds[level][level].compare_exchange(
+                    succs[level],
+                    node,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => break,
+                    Err(_) => {
+                        let (found_pred, found_succ) =
+                            self.find_on_level(key, seq, level, preds[level]);
+                        preds[level] = found_pred;
+                        succs[level] = found_succ;
+                    }
+                }
+            }
+        }
+        self.len.fetch_add(1, Ordering::Relaxed);
+        self.usage.fetch_add(usage, Ordering::Relaxed);
+        self.sequence.fetch_max(seq + 1, Ordering::AcqRel);
+    }
+
+    fn range(
+        &self,
+        start: Bound<&KeyT>,
+        end: Bound<&KeyT>,
+    ) -> Box<dyn Iterator<Item = EntryT> + '_> {
+        let mut node = match start {
+            Included(key) | Excluded(key) => self.seek(key, u64::MAX),
+            Unbounded => self.head[0].load(Ordering::Acquire),
+        };
+        if let Excluded(key) = start {
+            while let Some(found) = unsafe { node.as_ref() } {
+                if found.entry.key != *key {
+                    break;
+                }
+                node = found.next[0].load(Ordering::Acquire);
+            }
+        }
+        Box::new(SkipListIter {
+            node: unsafe { node.as_ref() },
+            end: end.map(KeyT::clone),
+        })
+    }
+
+    fn first_at(&self, start: Bound<&KeyT>, seq: u64) -> Option<EntryT> {
+        let mut node = match start {
+            Included(key) | Excluded(key) => self.seek(key, u64::MAX),
+            Unbounded => self.head[0].load(Ordering::Acquire),
+        };
+        //the nodes of a key come newest first, so the first one below seq wins
+        while let Some(found) = unsafe { node.as_ref() } {
+            let excluded = matches!(start, Excluded(key) if found.entry.key == *key);
+            if !excluded && found.seq < seq {
+                return Some(found.entry.clone());
+            }
+            node = found.next[0].load(Ordering::Acquire);
+        }
+        None
+    }
+
+    fn last_at(&self, end: Bound<&KeyT>, seq: u64) -> Option<EntryT> {
+        let mut below = match end {
+            Included(key) => match self.get_at(key, seq) {
+                Some(entry) => return Some(entry),
+                None => Some(key.as_slice()),
+            },
+            Excluded(key) => Some(key.as_slice()),
+            Unbounded => None,
+        };
+        //there are no links back, so every key is found from the head again
+        loop {
+            let node = self.last_below(below)?;
+            if let Some(entry) = self.get_at(&node.entry.key, seq) {
+                return Some(entry);
+            }
+            below = Some(node.entry.key.as_slice());
+        }
+    }
+
+    fn sequence(&self) -> u64 {
+        self.sequence.load(Ordering::Acquire)
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn approximate_memory_usage(&self) -> usize {
+        self.usage.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for SkipList {
+    fn drop(&mut self) {
+        let mut node = *self.head[0].get_mut();
+        while !node.is_null() {
+            //every node was made by Box::into_raw and is on level 0 exactly once
+            let mut owned = unsafe { Box::from_raw(node) };
+            node = *owned.next[0].get_mut();
+        }
+    }
+}
+
+//walks level 0, yielding the newest node of every key up to end
+struct SkipListIter<'a> {
+    node: Option<&'a Node>,
+    end: Bound<KeyT>,
+}
+
+impl<'a> Iterator for SkipListIter<'a> {
+    type Item = EntryT;
+
+    fn next(&mut self) -> Option<EntryT> {
+        let node = self.node?;
+        let in_range = match &self.end {
+            Included(end) => node.entry.key <= *end,
+            Excluded(end) => node.entry.key < *end,
+            Unbounded => true,
+        };
+        if !in_range {
+            self.node = None;
+            return None;
+        }
+        //skip the older nodes of the same key
+        let mut next = unsafe { node.next[0].load(Ordering::Acquire).as_ref() };
+        while let Some(older) = next {
+            if older.entry.key != node.entry.key {
+                break;
+            }
+            next = unsafe { older.next[0].load(Ordering::Acquire).as_ref() };
+        }
+        self.node = next;
+        Some(node.entry.clone())
+    }
+}
+
+#[test]
+fn test_parallel_put() {
+    use std::sync::Arc;
+    use std::thread;
+    let list = Arc::new(SkipList::new());
+    let num_threads = 8;
+    let puts_per_thread = 2000;
+    let handles: Vec<_> = (0..num_threads)
+        .map(|t| {
+            let list = list.clone();
+            thread::spawn(move || {
+                for i in 0..puts_per_thread {
+                    //every thread writes its own keys and one shared key
+                    let seq = (i * num_threads + t) as u64;
+                    let key = format!("{:05}", i * num_threads + t).into_bytes();
+                    list.put(EntryT::new(key, vec![t as u8]), seq);
+                    list.put(
+                        EntryT::new(b"shared".to_vec(), seq.to_le_bytes().to_vec()),
+                        seq,
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(2 * num_threads * puts_per_thread, list.len());
+    let entries: Vec<EntryT> = list.iter().collect();
+    //every key once, in order, and the shared key holds the highest sequence number
+    assert_eq!(num_threads * puts_per_thread + 1, entries.len());
+    assert!(entries.windows(2).all(|pair| pair[0].key < pair[1].key));
+    let last_seq = (num_threads * puts_per_thread - 1) as u64;
+    assert_eq!(
+        last_seq.to_le_bytes().to_vec(),
+        list.get(&b"shared".to_vec()).unwrap().value
+    );
+}
@@ -0,0 +1,548 @@
+//write-ahead log. every write is appended here before it goes to the buffer, so the
+//buffer can be rebuilt after a crash. a record holds the encoded entries of one write
+//back to back: a single entry for put and del, all of them for a write batch.
+//a log only covers one buffer. when the buffer is sealed its log is renamed to
+//wal-N.log, N being the file number of the run it becomes, and deleted once that run
+//is in level 0.
+use crate::data_type::EntryT;
+use crate::error::{Error, Result};
+use std::cmp::max;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub static WAL_FILE_NAME: &str = "wal.log";
+
+/// Name of the log of a full buffer that waits for its flush to run `number`
+pub fn sealed_log_name(number: u64) -> String {
+    format!("wal-{}.log", number)
+}
+
+/// The run number in a name made by sealed_log_name
+pub fn parse_sealed_log_name(name: &str) -> Option<u64> {
+    name.strip_prefix("wal-")?
+        .strip_suffix(".log")?
+        .parse()
+        .ok()
+}
+//a record starts with the crc32 of its payload and the payload length, both as little endian u32
+pub static RECORD_HEADER_SIZE: usize = 8;
+
+const fn make_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC_TABLE: [u32; 256] = make_crc_table();
+
+/// CRC-32 (IEEE) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Frames `payload` as a checksummed log record
+pub fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    record.extend_from_slice(&crc32(payload).to_le_bytes());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Splits `data` into the payloads of its records. Stops at the first record that is
+/// incomplete or fails its checksum, which is what a write torn by a crash looks like.
+/// Also returns the number of bytes taken by the intact records.
+pub fn decode_records(data: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut payloads = Vec::new();
+    let mut pos = 0;
+    while data.len() - pos >= RECORD_HEADER_SIZE {
+        let crc = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let start = pos + RECORD_HEADER_SIZE;
+        if data.len() - start < len || crc32(&data[start..start + len]) != crc {
+            break;
+        }
+        payloads.push(&data[start..start + len]);
+        pos = start + len;
+    }
+    (payloads, pos)
+}
+
+/// When appended records are forced to stable storage with fsync
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    /// Never fsync. Records survive the process being killed, but not a power loss.
+    NoSync,
+    /// fsync before the write returns.
+    EveryWrite,
+    /// fsync when the last fsync is older than the interval. A tree also syncs its log
+    /// once the interval after a record is over, so an idle tree loses at most that much.
+    Interval(Duration),
+    /// fsync once at least this many bytes were appended since the last fsync.
+    Bytes(u64),
+}
+
+struct WalState {
+    //bytes appended through this handle so far, and how many of them are synced
+    appended: u64,
+    synced: u64,
+    //some writer is running fsync for the whole group right now
+    syncing: bool,
+    last_sync: Instant,
+    sync_count: u64,
+    //length of the file up to the end of its last whole record
+    len: u64,
+    //set once a torn record could not be cut off again, every later write gets it
+    failed: Option<Error>,
+    //the next record is cut after this many bytes and fails, as on a full disk
+    #[cfg(test)]
+    cut_next_record: Option<usize>,
+}
+
+pub struct Wal {
+    file: File,
+    state: Mutex<WalState>,
+    sync_done: Condvar,
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if needed. New records are appended after the
+    /// existing ones.
+    pub fn open(path: &Path) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Wal {
+            file,
+            state: Mutex::new(WalState {
+                appended: 0,
+                synced: 0,
+                syncing: false,
+                last_sync: Instant::now(),
+                sync_count: 0,
+                len,
+                failed: None,
+                #[cfg(test)]
+                cut_next_record: None,
+            }),
+            sync_done: Condvar::new(),
+        })
+    }
+
+    /// Appends `entry` and, if `policy` asks for it, waits until it is on stable storage.
+    pub fn append(&self, entry: &EntryT, policy: SyncPolicy) -> Result<()> {
+        self.append_batch(std::slice::from_ref(entry), policy)
+    }
+
+    /// Appends `entries` as one record, so a crash keeps either all or none of them.
+    pub fn append_batch(&self, entries: &[EntryT], policy: SyncPolicy) -> Result<()> {
+        match self.write_record(entries, policy)? {
+            Some(target) => self.sync_to(target),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends `entries` as one record without waiting for a sync. Returns the offset to
+    /// pass to `sync_to` if `policy` asks for a sync, so the caller can drop its own
+    /// locks first.
+    pub fn write_record(&self, entries: &[EntryT], policy: SyncPolicy) -> Result<Option<u64>> {
+        let mut payload = Vec::new();
+        for entry in entries {
+            payload.extend_from_slice(&entry.encode());
+        }
+        let record = encode_record(&payload);
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = &state.failed {
+            return Err(err.clone());
+        }
+        //one write call per record, so a killed process leaves at most one torn record behind
+        if let Err(err) = self.write_all(&mut state, &record) {
+            //records appended behind a torn one would never be replayed, so it is cut off
+            if let Err(cut_err) = self.file.set_len(state.len) {
+                state.failed = Some(cut_err.into());
+            }
+            return Err(err.into());
+        }
+        state.len += record.len() as u64;
+        state.appended += record.len() as u64;
+        let need_sync = match policy {
+            SyncPolicy::NoSync => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::Interval(interval) => state.last_sync.elapsed() >= interval,
+            SyncPolicy::Bytes(bytes) => state.appended - state.synced >= bytes,
+        };
+        Ok(if need_sync {
+            Some(state.appended)
+        } else {
+            None
+        })
+    }
+
+    /// Forces every record appended so far to stable storage.
+    pub fn sync(&self) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        let target = state.appended;
+        self.wait_for_sync(state, target)
+    }
+
+    /// Forces the records up to `target`, as returned by `write_record`, to stable storage.
+    pub fn sync_to(&self, target: u64) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        self.wait_for_sync(state, target)
+    }
+
+    #[cfg(not(test))]
+    fn write_all(&self, _state: &mut WalState, record: &[u8]) -> std::io::Result<()> {
+        (&self.file).write_all(record)
+    }
+
+    #[cfg(test)]
+    fn write_all(&self, state: &mut WalState, record: &[u8]) -> std::io::Result<()> {
+        match state.cut_next_record.take() {
+            Some(cut) => {
+                (&self.file).write_all(&record[..cut])?;
+                Err(std::io::Error::other("no space left"))
+            }
+            None => (&self.file).write_all(record),
+        }
+    }
+
+    /// Number of fsyncs issued so far. With group commit it can be far lower than the
+    /// number of writes that asked for one.
+    pub fn sync_count(&self) -> u64 {
+        self.state.lock().unwrap().sync_count
+    }
+
+    //group commit: the first writer that needs a sync runs fsync for everything appended so far,
+    //writers arriving meanwhile wait for it and only sync again if their record came too late.
+    fn wait_for_sync<'a>(&'a self, mut state: MutexGuard<'a, WalState>, target: u64) -> Result<()> {
+        if let Some(err) = &state.failed {
+            return Err(err.clone());
+        }
+        loop {
+            if state.synced >= target {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.sync_done.wait(state).unwrap();
+                continue;
+            }
+            state.syncing = true;
+            let group_end = state.appended;
+            drop(state);
+            let res = self.file.sync_data();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if res.is_ok() {
+                state.synced = max(state.synced, group_end);
+                state.last_sync = Instant::now();
+                state.sync_count += 1;
+            }
+            self.sync_done.notify_all();
+            res?;
+        }
+    }
+
+    /// Reads back every intact entry in the log, oldest first. A torn record at the
+    /// end is cut off so that new records are not appended behind it.
+    pub fn replay(&self) -> Result<Vec<EntryT>> {
+        let mut data = Vec::new();
+        (&self.file).seek(SeekFrom::Start(0))?;
+        (&self.file).read_to_end(&mut data)?;
+        let (payloads, valid_len) = decode_records(&data);
+        let mut entries = Vec::with_capacity(payloads.len());
+        for payload in payloads {
+            let mut pos = 0;
+            while pos < payload.len() {
+                match EntryT::decode(&payload[pos..]) {
+                    Some((entry, used)) => {
+                        entries.push(entry);
+                        pos += used;
+                    }
+                    None => return Err(Error::corruption("corrupted entry in write-ahead log")),
+                }
+            }
+        }
+        if valid_len < data.len() {
+            self.file.set_len(valid_len as u64)?;
+        }
+        self.state.lock().unwrap().len = valid_len as u64;
+        Ok(entries)
+    }
+}
+
+/// Syncs logs once an interval after a record is over, for `SyncPolicy::Interval`. Its
+/// thread starts with the first log scheduled and stops when the timer is dropped.
+#[derive(Default)]
+pub struct SyncTimer {
+    shared: Arc<TimerShared>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Default)]
+struct TimerShared {
+    state: Mutex<TimerState>,
+    wake: Condvar,
+}
+
+#[derive(Default)]
+struct TimerState {
+    //every log waiting for a sync, with the time it is due
+    due: Vec<(Instant, Arc<Wal>)>,
+    stopped: bool,
+}
+
+impl SyncTimer {
+    pub fn new() -> SyncTimer {
+        SyncTimer::default()
+    }
+
+    /// Syncs `wal` at `deadline`, or earlier if it is already waiting for an earlier sync
+    pub fn schedule(&self, wal: &Arc<Wal>, deadline: Instant) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.due.iter().any(|(_, due)| Arc::ptr_eq(due, wal)) {
+            return;
+        }
+        state.due.push((deadline, Arc::clone(wal)));
+        drop(state);
+        self.shared.wake.notify_all();
+        let mut thread = self.thread.lock().unwrap();
+        if thread.is_none() {
+            let shared = Arc::clone(&self.shared);
+            *thread = Some(thread::spawn(move || shared.run()));
+        }
+    }
+}
+
+impl TimerShared {
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.stopped {
+            let now = Instant::now();
+            let (due, later): (Vec<_>, Vec<_>) =
+                state.due.drain(..).partition(|(at, _)| *at <= now);
+            state.due = later;
+            if !due.is_empty() {
+                drop(state);
+                for (_, wal) in due {
+                    //a failed sync leaves the records to the next sync, which reports it
+                    let _ = wal.sync();
+                }
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            state = match state.due.iter().map(|(at, _)| *at).min() {
+                Some(next) => self.wake.wait_timeout(state, next - now).unwrap().0,
+                None => self.wake.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl Drop for SyncTimer {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stopped = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[test]
+fn test_sealed_log_name() {
+    assert_eq!("wal-17.log", sealed_log_name(17));
+    assert_eq!(Some(17), parse_sealed_log_name("wal-17.log"));
+    assert_eq!(None, parse_sealed_log_name(WAL_FILE_NAME));
+    assert_eq!(None, parse_sealed_log_name("wal-x.log"));
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(0, crc32(b""));
+    assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+}
+
+#[test]
+fn test_append_replay() {
+    use std::fs;
+    let _ = fs::create_dir_all("/tmp/wal_unit_test");
+    let path = Path::new("/tmp/wal_unit_test/wal.log");
+    let _ = fs::remove_file(path);
+    let wal = Wal::open(path).unwrap();
+    wal.append(
+        &EntryT::new(b"k1".to_vec(), b"v1".to_vec()),
+        SyncPolicy::NoSync,
+    )
+    .unwrap();
+    wal.append(&EntryT::tombstone(b"k2".to_vec()), SyncPolicy::EveryWrite)
+        .unwrap();
+    let valid_len = fs::metadata(path).unwrap().len();
+    //simulate a crash in the middle of writing the third record
+    let torn = encode_record(&EntryT::new(b"k3".to_vec(), b"v3".to_vec()).encode());
+    (&wal.file).write_all(&torn[..torn.len() - 2]).unwrap();
+
+    let wal = Wal::open(path).unwrap();
+    let entries = wal.replay().unwrap();
+    assert_eq!(2, entries.len());
+    assert_eq!(b"v1".to_vec(), entries[0].value);
+    assert!(entries[1].is_tombstone());
+    assert_eq!(valid_len, fs::metadata(path).unwrap().len());
+}
+
+#[test]
+fn test_failed_append() {
+    use crate::data_type::KeyT;
+    use std::fs;
+    let _ = fs::create_dir_all("/tmp/wal_unit_test");
+    let path = Path::new("/tmp/wal_unit_test/failed_append.log");
+    let _ = fs::remove_file(path);
+    let wal = Wal::open(path).unwrap();
+    let entry = |key: &str| EntryT::new(key.as_bytes().to_vec(), b"v".to_vec());
+    wal.append(&entry("k1"), SyncPolicy::NoSync).unwrap();
+    //the second record is torn by a failed write, the third one goes in fine
+    wal.state.lock().unwrap().cut_next_record = Some(5);
+    assert!(wal.append(&entry("k2"), SyncPolicy::NoSync).is_err());
+    wal.append(&entry("k3"), SyncPolicy::EveryWrite).unwrap();
+    let keys: Vec<KeyT> = Wal::open(path)
+        .unwrap()
+        .replay()
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.key)
+        .collect();
+    assert_eq!(vec![b"k1".to_vec(), b"k3".to_vec()], keys);
+}
+
+#[test]
+fn test_append_batch() {
+    use std::fs;
+    let _ = fs::create_dir_all("/tmp/wal_unit_test");
+    let path = Path::new("/tmp/wal_unit_test/batch.log");
+    let _ = fs::remove_file(path);
+    let wal = Wal::open(path).unwrap();
+    wal.append(
+        &EntryT::new(b"k0".to_vec(), b"v0".to_vec()),
+        SyncPolicy::NoSync,
+    )
+    .unwrap();
+    let batch = vec![
+        EntryT::new(b"k1".to_vec(), b"v1".to_vec()),
+        EntryT::tombstone(b"k0".to_vec()),
+    ];
+    wal.append_batch(&batch, SyncPolicy::NoSync).unwrap();
+    let valid_len = fs::metadata(path).unwrap().len();
+    //a batch torn by a crash is dropped as a whole
+    let mut payload = batch[0].encode();
+    payload.extend_from_slice(&batch[1].encode());
+    let torn = encode_record(&payload);
+    (&wal.file).write_all(&torn[..torn.len() - 1]).unwrap();
+
+    let wal = Wal::open(path).unwrap();
+    let entries = wal.replay().unwrap();
+    assert_eq!(3, entries.len());
+    assert_eq!(b"v0".to_vec(), entries[0].value);
+    assert_eq!(b"k1".to_vec(), entries[1].key);
+    assert!(entries[2].is_tombstone());
+    assert_eq!(valid_len, fs::metadata(path).unwrap().len());
+}
+
+#[test]
+fn test_sync_policy() {
+    use std::fs;
+    let _ = fs::create_dir_all("/tmp/wal_unit_test");
+    let path = Path::new("/tmp/wal_unit_test/sync_policy.log");
+    let _ = fs::remove_file(path);
+    let wal = Wal::open(path).unwrap();
+    let entry = EntryT::new(b"key".to_vec(), b"value".to_vec());
+    let record_len = encode_record(&entry.encode()).len() as u64;
+
+    for _ in 0..10 {
+        wal.append(&entry, SyncPolicy::NoSync).unwrap();
+    }
+    assert_eq!(0, wal.sync_count());
+    //the first append crosses the threshold because of the ten unsynced records before it
+    for _ in 0..10 {
+        wal.append(&entry, SyncPolicy::Bytes(record_len * 5))
+            .unwrap();
+    }
+    assert_eq!(2, wal.sync_count());
+    wal.append(&entry, SyncPolicy::Interval(Duration::from_secs(3600)))
+        .unwrap();
+    assert_eq!(2, wal.sync_count());
+    wal.append(&entry, SyncPolicy::Interval(Duration::from_millis(0)))
+        .unwrap();
+    assert_eq!(3, wal.sync_count());
+    wal.append(&entry, SyncPolicy::EveryWrite).unwrap();
+    assert_eq!(4, wal.sync_count());
+    //nothing new to sync
+    wal.sync().unwrap();
+    assert_eq!(4, wal.sync_count());
+    //write_record leaves the sync to the caller
+    let entries = std::slice::from_ref(&entry);
+    assert_eq!(None, wal.write_record(entries, SyncPolicy::NoSync).unwrap());
+    let target = wal.write_record(entries, SyncPolicy::EveryWrite).unwrap();
+    assert!(target.is_some());
+    assert_eq!(4, wal.sync_count());
+    wal.sync_to(target.unwrap()).unwrap();
+    assert_eq!(5, wal.sync_count());
+}
+
+#[test]
+fn test_group_commit() {
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    let _ = fs::create_dir_all("/tmp/wal_unit_test");
+    let path = Path::new("/tmp/wal_unit_test/group_commit.log");
+    let _ = fs::remove_file(path);
+    let wal = Arc::new(Wal::open(path).unwrap());
+    let num_threads = 8;
+    let writes_per_thread = 50;
+    let handles: Vec<_> = (0..num_threads)
+        .map(|t| {
+            let wal = Arc::clone(&wal);
+            thread::spawn(move || {
+                for i in 0..writes_per_thread {
+                    let key = format!("{}-{}", t, i).into_bytes();
+                    wal.append(&EntryT::new(key, vec![t as u8]), SyncPolicy::EveryWrite)
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(
+        (num_threads * writes_per_thread) as usize,
+        wal.replay().unwrap().len()
+    );
+}